@@ -4,9 +4,15 @@ use serde::{Deserialize, Serialize};
 use typescript_type_def::{write_definition_file,DefinitionFileOptions};
 include!("src/json.rs");
 
+#[path = "src/routes.rs"]
+mod routes;
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    embuild::build::CfgArgs::output_propagated("ESP_IDF")?;
-    embuild::build::LinkArgs::output_propagated("ESP_IDF")?;
+    #[cfg(feature = "esp")]
+    {
+        embuild::build::CfgArgs::output_propagated("ESP_IDF")?;
+        embuild::build::LinkArgs::output_propagated("ESP_IDF")?;
+    }
     // now call write_definition_file::<_, Foo>(&mut buf, options)
     // on a writer that outputs to OUT_DIR/json.ts
     // first create a string representing OUT_DIR/json.ts
@@ -16,5 +22,70 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut file = std::fs::File::create(json_path)?;
     let options = DefinitionFileOptions::default();
     write_definition_file::<_, API>(&mut file, options)?;
+
+    // and the list of endpoints that use those types
+    let routes_path = std::path::Path::new(&out_dir).join("routes.json");
+    let listing = serde_json::json!({ "prefix": routes::API_PREFIX, "routes": routes::ROUTES });
+    serde_json::to_writer_pretty(std::fs::File::create(routes_path)?, &listing)?;
+    println!("cargo:rerun-if-changed=src/routes.rs");
+
+    write_assets(std::path::Path::new("www/dist"), std::path::Path::new(&std::env::var("OUT_DIR")?))?;
     Ok(())
 }
+
+/// Gzip every file of the bundle that `pnpm build` leaves in www/dist into OUT_DIR and write
+/// OUT_DIR/assets.rs, the table that src/assets.rs includes. Without a bundle a placeholder
+/// page is served instead, so the firmware still builds without node.
+fn write_assets(dist : &std::path::Path, out : &std::path::Path) -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::Write;
+    println!("cargo:rerun-if-changed={}", dist.display());
+
+    let mut files : Vec<(String, Vec<u8>)> = Vec::new();
+    if dist.is_dir() {
+        for entry in std::fs::read_dir(dist)? {
+            let path = entry?.path();
+            if path.is_file() {
+                let name = path.file_name().unwrap().to_string_lossy().into_owned();
+                files.push((name, std::fs::read(&path)?));
+            }
+        }
+        files.sort();
+    } else {
+        println!("cargo:warning=www/dist is missing, run `pnpm build` in www/ to serve the web interface");
+        files.push(("index.html".to_string(),
+            b"<html><body>Build the web interface with <code>pnpm build</code> in www/ and reflash.</body></html>".to_vec()));
+    }
+
+    let mut table = std::fs::File::create(out.join("assets.rs"))?;
+    writeln!(table, "pub const ASSETS : &[Asset] = &[")?;
+    for (name, contents) in files {
+        let gz_name = format!("{}.gz", name);
+        let mut gz = flate2::write::GzEncoder::new(std::fs::File::create(out.join(&gz_name))?, flate2::Compression::best());
+        gz.write_all(&contents)?;
+        gz.finish()?;
+
+        let path = if name == "index.html" { "/".to_string() } else { format!("/{}", name) };
+        writeln!(table, "    Asset {{ path : {:?}, content_type : {:?}, etag : \"\\\"{:016x}\\\"\", gz : include_bytes!(concat!(env!(\"OUT_DIR\"), \"/{}\")) }},",
+            path, content_type(&name), fnv1a(&contents), gz_name)?;
+    }
+    writeln!(table, "];")?;
+    Ok(())
+}
+
+fn content_type(name : &str) -> &'static str {
+    match name.rsplit('.').next() {
+        Some("html") => "text/html; charset=utf-8",
+        Some("js") => "text/javascript",
+        Some("css") => "text/css",
+        Some("json") | Some("map") => "application/json",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("ico") => "image/x-icon",
+        _ => "application/octet-stream",
+    }
+}
+
+/// for the ETag. Unlike DefaultHasher it doesn't change between compiler versions
+fn fnv1a(bytes : &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |h, &b| (h ^ b as u64).wrapping_mul(0x100000001b3))
+}
@@ -1,17 +1,28 @@
-use esp_idf_hal::{gpio::ADCPin, adc::{Adc, AdcDriver, AdcChannelDriver, Atten0dB}, peripheral::Peripheral};
+use esp_idf_hal::{gpio::ADCPin, peripheral::Peripheral};
 
+use crate::{Attenuation, adc, hal::{ConvertedRead, CurrentSensor}};
 
-pub struct ACS172<'d, PIN : ADCPin, ADC : Adc > {
-    pub driver : AdcDriver<'d, ADC>,
-    pub channel : AdcChannelDriver<'d, PIN, Atten0dB<<PIN as ADCPin>::Adc>>,
+/// volts, the adc pin can't go above the supply. Anything larger in a saved calibration is
+/// from the firmware that read adc counts
+pub const MAX_V : f32 = 3.3;
+
+/// the ACS712 output in volts, which the calibration maps to amps
+pub struct ACS172<'d> {
+    mv : Box<dyn ConvertedRead + Send + 'd>,
 }
 
-impl<'d, PIN : ADCPin, ADC : Adc> ACS172<'d, PIN, ADC> {
-    pub fn new(pin : impl Peripheral<P = PIN> + 'd,
-               adc : impl Peripheral<P = ADC> + 'd) -> anyhow::Result<Self> {
-        let conf = Default::default();
-        let driver = AdcDriver::new(adc, &conf)?;
-        let channel : AdcChannelDriver<_, Atten0dB<_>> = AdcChannelDriver::new(pin)?;
-        Ok(Self { driver, channel })
+impl<'d> ACS172<'d> {
+    pub fn new<PIN : ADCPin>(pin : impl Peripheral<P = PIN> + 'd,
+                             adc : impl Peripheral<P = PIN::Adc> + 'd,
+                             atten : Attenuation) -> anyhow::Result<Self> {
+        Ok(Self { mv : adc::millivolts(pin, adc, atten)? })
     }
 }
+
+impl<'d> ConvertedRead for ACS172<'d> {
+    fn read(&mut self) -> anyhow::Result<f32> {
+        Ok(self.mv.read()? / 1000.0)
+    }
+}
+
+impl<'d> CurrentSensor for ACS172<'d> {}
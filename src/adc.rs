@@ -0,0 +1,66 @@
+//! Adc readings in millivolts. ESP-IDF corrects each chip with the values burned into its
+//! eFuses at the factory (curve fitting on the esp32c3, line fitting on older chips), so a
+//! [crate::Calibration] of volts made on one board still holds on another.
+//!
+//! The attenuation is a type parameter of the channel driver, [millivolts] picks it from the
+//! [Attenuation] in the hardware settings.
+
+use crate::Attenuation;
+
+impl Attenuation {
+    /// top of the range the esp32c3 reads accurately
+    pub fn full_scale_mv(&self) -> f32 {
+        match self {
+            Attenuation::Db0 => 750.0,
+            Attenuation::Db2_5 => 1050.0,
+            Attenuation::Db6 => 1300.0,
+            Attenuation::Db11 => 2500.0,
+        }
+    }
+}
+
+#[cfg(feature = "esp")]
+pub use esp::{millivolts, MilliVolts};
+
+#[cfg(feature = "esp")]
+mod esp {
+    use anyhow::anyhow;
+    use esp_idf_hal::{adc::{self, AdcChannelDriver, AdcDriver, Atten0dB, Atten11dB, Atten2p5dB, Atten6dB, Attenuation as Atten},
+                      gpio::ADCPin, peripheral::Peripheral};
+    use esp_idf_sys::EspError;
+
+    use crate::{Attenuation, hal::ConvertedRead};
+
+    pub struct MilliVolts<'d, PIN : ADCPin, ATTEN> {
+        driver : AdcDriver<'d, PIN::Adc>,
+        channel : AdcChannelDriver<'d, PIN, ATTEN>,
+    }
+
+    impl<'d, PIN : ADCPin, ATTEN : Atten<PIN::Adc>> MilliVolts<'d, PIN, ATTEN> {
+        pub fn new(pin : impl Peripheral<P = PIN> + 'd, adc : impl Peripheral<P = PIN::Adc> + 'd) -> anyhow::Result<Self> {
+            // with calibration, read converts with the eFuse characteristics of ATTEN
+            let driver = AdcDriver::new(adc, &adc::config::Config::new().calibration(true))?;
+            Ok(MilliVolts { driver, channel : AdcChannelDriver::new(pin)? })
+        }
+    }
+
+    impl<'d, PIN : ADCPin, ATTEN : Atten<PIN::Adc>> ConvertedRead for MilliVolts<'d, PIN, ATTEN> {
+        fn read(&mut self) -> anyhow::Result<f32> {
+            self.driver.read(&mut self.channel)
+                .map_err(|e : EspError| anyhow!("adc error: {:?}", e))
+                .map(|mv| mv as f32)
+        }
+    }
+
+    /// mV on `pin` at `atten`
+    pub fn millivolts<'d, PIN : ADCPin>(pin : impl Peripheral<P = PIN> + 'd,
+                                        adc : impl Peripheral<P = PIN::Adc> + 'd,
+                                        atten : Attenuation) -> anyhow::Result<Box<dyn ConvertedRead + Send + 'd>> {
+        Ok(match atten {
+            Attenuation::Db0 => Box::new(MilliVolts::<_, Atten0dB<_>>::new(pin, adc)?),
+            Attenuation::Db2_5 => Box::new(MilliVolts::<_, Atten2p5dB<_>>::new(pin, adc)?),
+            Attenuation::Db6 => Box::new(MilliVolts::<_, Atten6dB<_>>::new(pin, adc)?),
+            Attenuation::Db11 => Box::new(MilliVolts::<_, Atten11dB<_>>::new(pin, adc)?),
+        })
+    }
+}
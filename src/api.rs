@@ -0,0 +1,331 @@
+//! Handlers for the routes in routes.rs. They only see the board through the traits in
+//! hal.rs, so the same handlers run on the esp32 and on the host.
+
+use std::sync::{Arc, Mutex};
+
+use crate::{*, auth::{self, Auth}, hal::{Clock, Dial, Shutdown}, linearly_calibrated::CalibratedSensor,
+            router::{Router, ApiError, HttpResponse, Body}, store::{self, BlobStore}, meas::Meas};
+
+/// key in the "calib" namespace of the number of steps between the lower and upper limits
+const STEPPER_CALIB : &str = "stepper_calib";
+
+/// key in the "calib" namespace of [Hardware]
+const HARDWARE : &str = "hardware";
+
+/// what the handlers share with the profile thread and the measurement loop
+#[derive(Clone)]
+pub struct Shared {
+    pub config : Arc<Mutex<Config>>,
+    /// see [crate::control::follow_profile]
+    pub step_index_completed : Arc<Mutex<usize>>,
+    /// set by the manual stepper endpoints so that the profile thread
+    /// doesn't undo them, until /stepper/resume
+    pub manual_stepper : Arc<Mutex<bool>>,
+    pub dial : Arc<Mutex<dyn Dial + Send>>,
+    pub shutdown : Arc<Mutex<dyn Shutdown + Send>>,
+    /// `[current, scale]`
+    pub calibrated_sensors : Arc<Mutex<[CalibratedSensor<'static>; 2]>>,
+    /// the "calib" namespace
+    pub calib : Arc<Mutex<dyn BlobStore + Send>>,
+    /// the compressed measurements, named by [crate::nvs::Key]
+    pub comp : Arc<Mutex<dyn BlobStore + Send>>,
+    pub auth : Arc<Auth>,
+    /// the latest sample, written by [crate::control::sample_blob]
+    pub status : Arc<Mutex<Status>>,
+    pub clock : Arc<dyn Clock + Send + Sync>,
+    /// see [crate::control::follow_run]
+    pub run : Arc<Mutex<control::Run>>,
+}
+
+impl CalibrationRequest {
+    fn apply(&self, calib : &mut [CalibratedSensor], now : i64) -> anyhow::Result<()>{
+        for (i, &save) in self.save.iter().enumerate() {
+            if self.clear[i] {
+                calib[i].calibration.clear();
+            }
+            if let Some(fit) = self.fit[i] {
+                calib[i].calibration.set_fit(fit);
+            }
+            if let Some(y) = self.y[i] {
+                calib[i].tare_measurement(y)?;
+            }
+            if save {
+                calib[i].save_calibration(now, CalibrationTrigger::Request)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// half steps between the limits of the dial, 170 unless `POST /stepper/calibrate` saved another
+pub fn stepper_len(calib : &Mutex<dyn BlobStore + Send>) -> i32 {
+    store::load(&*calib.lock().unwrap(), STEPPER_CALIB).ok().flatten().unwrap_or(170)
+}
+
+fn set_stepper_len(calib : &Mutex<dyn BlobStore + Send>, x : i32) -> anyhow::Result<()> {
+    store::save(&mut *calib.lock().unwrap(), STEPPER_CALIB, &x)
+}
+
+impl Hardware {
+    pub fn current(&self) -> CurrentSource {
+        self.current
+    }
+    pub fn trays(&self) -> &[TraySensor] {
+        &self.trays
+    }
+    pub fn scale_gain(&self) -> Gain {
+        self.scale_gain.unwrap_or(Gain::A128)
+    }
+    /// the one set or the one the wiring of [Hardware::current] was designed for
+    pub fn current_attenuation(&self) -> Attenuation {
+        self.current_attenuation.unwrap_or(match self.current {
+            CurrentSource::Sct013 { .. } => Attenuation::Db11,
+            _ => Attenuation::Db0,
+        })
+    }
+}
+
+/// the sensors fitted, as saved by `POST /hardware`, the ACS712 when nothing was saved
+pub fn hardware(calib : &Mutex<dyn BlobStore + Send>) -> Hardware {
+    store::load(&*calib.lock().unwrap(), HARDWARE).ok().flatten().unwrap_or_default()
+}
+
+fn stepper_status(d : &(impl Dial + ?Sized), manual : bool) -> StepperStatus {
+    let (min, max, pos) = d.position();
+    StepperStatus { min, max, pos, manual }
+}
+
+/// everything in [crate::routes::ROUTES]
+pub fn register(router : &mut Router, shared : &Shared) {
+    // exchange the password for a session cookie
+    let auth1 = shared.auth.clone();
+    router.raw(&routes::LOGIN, move |rq| {
+        let login : LoginRequest = router::read_json(rq)?;
+        let token = auth1.login(&login.password)?;
+        Ok(HttpResponse::new(200).header("Set-Cookie", auth::cookie(&token)))
+    });
+
+    // change the password
+    let auth1 = shared.auth.clone();
+    router.json(&routes::PASSWORD, move |password : PasswordRequest| {
+        Ok(auth1.set_password(&password.password, password.open_reads)?)
+    });
+
+    // set/save calibration
+    let calibrated_sensors1 = shared.calibrated_sensors.clone();
+    let clock1 = shared.clock.clone();
+    router.json(&routes::CALIB_POST, move |calib_rq : CalibrationRequest| {
+        let mut cs = calibrated_sensors1.lock().unwrap();
+        Ok(calib_rq.apply(&mut *cs, clock1.now())?)
+    });
+
+    // report the current calibrations and how well they fit their points
+    let calibrated_sensors1 = shared.calibrated_sensors.clone();
+    router.json(&routes::CALIB_GET, move |()| {
+        let cs = calibrated_sensors1.lock().unwrap();
+        Ok([cs[0].report(), cs[1].report()])
+    });
+
+    // every saved calibration and the drift between them
+    let calibrated_sensors1 = shared.calibrated_sensors.clone();
+    router.json(&routes::CALIB_HISTORY, move |()| {
+        let cs = calibrated_sensors1.lock().unwrap();
+        Ok([cs[0].history()?, cs[1].history()?])
+    });
+
+    let calibrated_sensors1 = shared.calibrated_sensors.clone();
+    let clock1 = shared.clock.clone();
+    router.json(&routes::CALIB_ROLLBACK, move |rq : RollbackRequest| {
+        let mut cs = calibrated_sensors1.lock().unwrap();
+        match cs.get_mut(rq.sensor) {
+            Some(sensor) => Ok(sensor.rollback(rq.time, clock1.now())?),
+            None => Err(ValidationErrors::field("sensor", "must be 0 (current) or 1 (scale)").into()),
+        }
+    });
+
+    // the trays are empty, or nothing runs through the current sensor
+    let calibrated_sensors1 = shared.calibrated_sensors.clone();
+    let clock1 = shared.clock.clone();
+    router.json(&routes::ZERO, move |rq : ZeroRequest| {
+        let mut cs = calibrated_sensors1.lock().unwrap();
+        match cs.get_mut(rq.sensor) {
+            Some(sensor) => Ok(sensor.zero(ZeroTrigger::Request, clock1.now())?),
+            None => Err(ValidationErrors::field("sensor", "must be 0 (current) or 1 (scale)").into()),
+        }
+    });
+
+    // ends the run as well, so the next config posted starts one
+    let shutdown1 = shared.shutdown.clone();
+    let run1 = shared.run.clone();
+    let calibrated_sensors1 = shared.calibrated_sensors.clone();
+    router.json(&routes::SHUTDOWN, move |()| {
+        control::shut_down(&*shutdown1, &calibrated_sensors1)?;
+        run1.lock().unwrap().going = false;
+        Ok(())
+    });
+
+    let i_min = shared.step_index_completed.clone();
+    let run1 = shared.run.clone();
+    let config1 = shared.config.clone();
+    let calibrated_sensors1 = shared.calibrated_sensors.clone();
+    let clock1 = shared.clock.clone();
+    router.json(&routes::RESTART, move |()| {
+        *i_min.lock().unwrap() = 0;
+        run1.lock().unwrap().start();
+        if config1.lock().unwrap().zero_scale_at_start {
+            calibrated_sensors1.lock().unwrap()[1].zero(ZeroTrigger::RunStart, clock1.now())?;
+        }
+        Ok(())
+    });
+
+    // get config
+    let config1 = shared.config.clone();
+    router.json(&routes::CONFIG_GET, move |()| {
+        Ok(config1.lock().unwrap().clone())
+    });
+
+    // set config
+    let i_min = shared.step_index_completed.clone();
+    let config1 = shared.config.clone();
+    let clock1 = shared.clock.clone();
+    let calibrated_sensors1 = shared.calibrated_sensors.clone();
+    let run1 = shared.run.clone();
+    router.json(&routes::CONFIG_POST, move |mut read_conf : Config| {
+        read_conf.validate()?;
+        let zero_scale = {
+            let mut config = config1.lock().unwrap();
+
+            let mut i_min = i_min.lock().unwrap();
+            let mut run = run1.lock().unwrap();
+            // with no run going this is the profile of a new one, during a run the
+            // profile starts over only if the steps already taken are unchanged
+            let new_run = !run.going;
+            let started = new_run || (config.step_times[..*i_min] == read_conf.step_times[..*i_min] &&
+                config.step_fracs[..*i_min] == read_conf.step_fracs[..*i_min]);
+            if started {
+                *i_min = 0;
+                read_conf.last_modified = clock1.now();
+            };
+            if new_run {
+                run.start();
+            }
+
+            *config = read_conf;
+            // a profile posted again during a run mustn't zero the loaded scale
+            new_run && config.zero_scale_at_start
+        };
+        if zero_scale {
+            calibrated_sensors1.lock().unwrap()[1].zero(ZeroTrigger::RunStart, clock1.now())?;
+        }
+        Ok(())
+    });
+
+    let dial1 = shared.dial.clone();
+    let manual1 = shared.manual_stepper.clone();
+    router.json(&routes::STEPPER_GET, move |()| {
+        let manual = *manual1.lock().unwrap();
+        Ok(stepper_status(&*dial1.lock().unwrap(), manual))
+    });
+
+    // jog, go to a fraction, or recalibrate the stepper and report where it was and
+    // where it ended up. The profile thread is paused from then on.
+    let manual_move = {
+        let dial = shared.dial.clone();
+        let manual = shared.manual_stepper.clone();
+        move |f : &dyn Fn(&mut dyn Dial) -> Result<(), ApiError>| -> Result<StepperMove, ApiError> {
+            *manual.lock().unwrap() = true;
+            let mut d = dial.lock().unwrap();
+            let before = stepper_status(&*d, true);
+            f(&mut *d)?;
+            Ok(StepperMove { before, after : stepper_status(&*d, true) })
+        }
+    };
+
+    let manual_move1 = manual_move.clone();
+    router.json(&routes::STEPPER_JOG, move |jog : JogRequest| {
+        manual_move1(&|d| Ok(d.jog(jog.steps)?))
+    });
+
+    let manual_move1 = manual_move.clone();
+    router.json(&routes::STEPPER_FRACTION, move |rq : FractionRequest| {
+        if !(0.0..=1.0).contains(&rq.fraction) {
+            return Err(ValidationErrors::field("fraction", format!("{} is outside [0,1]", rq.fraction)).into());
+        }
+        manual_move1(&|d| Ok(d.set_fraction(rq.fraction)?))
+    });
+
+    let calib1 = shared.calib.clone();
+    router.json(&routes::STEPPER_CALIBRATE, move |rq : StepperCalibrateRequest| {
+        let length = rq.length.unwrap_or_else(|| stepper_len(&calib1));
+        if !(1..=2048).contains(&length) {
+            return Err(ValidationErrors::field("length", "must be between 1 and 2048 half steps (4 turns)").into());
+        }
+        let moved = manual_move(&|d| Ok(d.recalibrate(length)?))?;
+        if rq.save {
+            set_stepper_len(&calib1, length)?;
+        }
+        Ok(moved)
+    });
+
+    // hand the stepper back to the profile thread, which moves it to where the
+    // profile says it should be by now
+    let dial1 = shared.dial.clone();
+    let manual1 = shared.manual_stepper.clone();
+    let i_min = shared.step_index_completed.clone();
+    router.json(&routes::STEPPER_RESUME, move |()| {
+        *manual1.lock().unwrap() = false;
+        *i_min.lock().unwrap() = 0;
+        Ok(stepper_status(&*dial1.lock().unwrap(), false))
+    });
+
+    let calib1 = shared.calib.clone();
+    router.json(&routes::HARDWARE_GET, move |()| {
+        Ok(hardware(&calib1))
+    });
+
+    // takes effect at the next boot, since the drivers own their pins
+    let calib1 = shared.calib.clone();
+    router.json(&routes::HARDWARE_POST, move |hw : Hardware| {
+        hw.validate()?;
+        Ok(store::save(&mut *calib1.lock().unwrap(), HARDWARE, &hw)?)
+    });
+
+    let status1 = shared.status.clone();
+    router.json(&routes::STATUS, move |()| {
+        Ok(status1.lock().unwrap().clone())
+    });
+
+    // get measurement
+    csv(router, &routes::MEASUREMENT_CSV, &shared.comp, meas::write_csv_header, meas::write_csv_rows);
+    // and every climate sensor separately
+    csv(router, &routes::SENSORS_CSV, &shared.comp, meas::write_sensors_csv_header, meas::write_sensors_csv_rows);
+}
+
+type CsvRows = fn(&mut dyn std::io::Write, &str, &Meas<Vec<f32>>) -> std::io::Result<()>;
+
+/// stream `header` and then `rows` of every measurement blob
+fn csv(router : &mut Router,
+       route : &'static routes::Route,
+       comp : &Arc<Mutex<dyn BlobStore + Send>>,
+       header : fn(&mut dyn std::io::Write) -> std::io::Result<()>,
+       rows : CsvRows) {
+    let comp1 = comp.clone();
+    router.raw(route, move |_| {
+        let comp1 = comp1.clone();
+        Ok(HttpResponse::new(200)
+            .header("Content-Type", "text/csv")
+            .body(Body::Stream(Box::new(move |rsp| {
+                header(rsp)?;
+
+                // body, one blob at a time so that the others stay in flash
+                let keys = nvs::comp_keys(&*comp1.lock().unwrap())?;
+                for j in keys {
+                    let blob : Option<Meas<Vec<u8>>> = store::load(&*comp1.lock().unwrap(), &j.to_string())?;
+                    if let Some(b) = blob {
+                        rows(rsp, &j.to_string(), &meas::decompress(b))?;
+                    }
+                }
+                Ok(())
+            }))))
+    });
+}
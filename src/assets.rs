@@ -0,0 +1,19 @@
+/// a file of the web interface, gzipped by /build.rs
+pub struct Asset {
+    /// "/" for index.html
+    pub path : &'static str,
+    pub content_type : &'static str,
+    /// quoted hash of the uncompressed file
+    pub etag : &'static str,
+    pub gz : &'static [u8],
+}
+
+impl Asset {
+    /// index.html has to be revalidated so that a reflash shows up. Parcel puts a
+    /// hash in the other names so they can be cached for good
+    pub fn cache_control(&self) -> &'static str {
+        if self.path == "/" { "no-cache" } else { "public, max-age=31536000, immutable" }
+    }
+}
+
+include!(concat!(env!("OUT_DIR"), "/assets.rs"));
@@ -0,0 +1,426 @@
+use std::sync::{Arc, Mutex};
+
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+
+use crate::{ValidationErrors, store::{self, BlobStore}, routes::Access, router::{ApiError, HttpRequest, HttpResponse, Body},
+            hal::{Clock, SystemClock}};
+
+/// blob name of the [Credentials] in the "auth" namespace
+const CREDENTIALS : &str = "credentials";
+
+/// how long a cookie from `/login` stays valid
+const SESSION_SECONDS : i64 = 12 * 3600;
+
+/// the oldest session is dropped when there are more than this many
+const MAX_SESSIONS : usize = 4;
+
+/// failed attempts allowed before the lockout starts doubling
+const FREE_FAILURES : u32 = 3;
+const MAX_LOCKOUT_SECONDS : i64 = 300;
+
+/// PBKDF2 iterations for a new password. The hash is in nvs, which can be read out of the
+/// flash, so every guess should cost. Only a login or a `Bearer` header pays for it, the
+/// ui uses the session cookie. The unoptimized tests would take minutes with as many
+const ROUNDS : u32 = if cfg!(test) { 64 } else { 10_000 };
+
+/// what is stored in flash: never the password itself
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
+struct Credentials {
+    salt : [u8; 16],
+    hash : [u8; 32],
+    /// GET requests do not need the password
+    open_reads : bool,
+    /// of PBKDF2-HMAC-SHA256, 0 for a single salted SHA-256 saved by older firmware
+    #[serde(default)]
+    rounds : u32,
+}
+
+impl Credentials {
+    fn new(password : &str, open_reads : bool) -> anyhow::Result<Self> {
+        let mut salt = [0u8; 16];
+        getrandom::getrandom(&mut salt)?;
+        let hash = hash(&salt, password, ROUNDS);
+        Ok(Credentials { salt, hash, open_reads, rounds : ROUNDS })
+    }
+
+    fn matches(&self, password : &str) -> bool {
+        constant_time_eq(&hash(&self.salt, password, self.rounds), &self.hash)
+    }
+}
+
+fn hash(salt : &[u8], password : &str, rounds : u32) -> [u8; 32] {
+    let mut out = [0u8; 32];
+    if rounds == 0 {
+        let mut h = Sha256::new();
+        h.update(salt);
+        h.update(password.as_bytes());
+        out = h.finalize().into();
+    } else {
+        pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, rounds, &mut out);
+    }
+    out
+}
+
+/// compare without returning early, so the time taken doesn't reveal the length of the common prefix
+fn constant_time_eq(a : &[u8], b : &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// reasons to refuse a request
+#[derive(Debug)]
+pub enum Denied {
+    /// missing or wrong password or session
+    Unauthorized,
+    /// too many failures: try again after this many seconds
+    RateLimited(i64),
+    /// no password was provisioned, so there is none to change
+    Unprovisioned,
+}
+
+impl Denied {
+    /// 401 or 429
+    pub fn response(&self) -> HttpResponse {
+        match self {
+            Denied::Unauthorized => HttpResponse::new(401).header("WWW-Authenticate", "Bearer"),
+            Denied::RateLimited(s) => HttpResponse::new(429).header("Retry-After", s.to_string()),
+            Denied::Unprovisioned => HttpResponse::new(403)
+                .header("Content-Type", "text/plain")
+                .body(Body::Static(b"no password was provisioned, build the firmware with DEHYDRATOR_PASSWORD set")),
+        }
+    }
+}
+
+struct AuthState {
+    /// None until a password is provisioned, and until then every endpoint is open
+    credentials : Option<Credentials>,
+    /// (cookie value, expiry time)
+    sessions : Vec<(String, i64)>,
+    failures : u32,
+    locked_until : i64,
+}
+
+/// Checks the `Authorization: Bearer <password>` header or the `session` cookie
+/// handed out by [Auth::login]. The password is hashed with a random salt and kept
+/// in the "auth" namespace of the default nvs partition.
+///
+/// Only wrong passwords count towards the lockout, and it doesn't hold back a session
+/// that is already open. A cookie that isn't a session, like one from before a reboot,
+/// is refused without a penalty: the cookies are random, guessing one is hopeless.
+pub struct Auth {
+    nvs : Arc<Mutex<dyn BlobStore + Send>>,
+    state : Mutex<AuthState>,
+    clock : Arc<dyn Clock + Send + Sync>,
+}
+
+impl Auth {
+    pub fn load(nvs : Arc<Mutex<dyn BlobStore + Send>>) -> anyhow::Result<Self> {
+        let credentials = store::load(&*nvs.lock().unwrap(), CREDENTIALS)?;
+        Ok(Auth {
+            nvs,
+            state : Mutex::new(AuthState { credentials, sessions : Vec::new(), failures : 0, locked_until : 0 }),
+            clock : Arc::new(SystemClock),
+        })
+    }
+
+    /// for the session expiry and the lockout, instead of the system clock
+    pub fn with_clock(self, clock : Arc<dyn Clock + Send + Sync>) -> Self {
+        Auth { clock, ..self }
+    }
+
+    /// Ok(()) if the request may proceed
+    pub fn check(&self, rq : &HttpRequest, access : Access) -> Result<(), Denied> {
+        if access == Access::Open {
+            return Ok(());
+        }
+        let mut st = self.state.lock().unwrap();
+        let open_reads = match &st.credentials {
+            None => return Ok(()),
+            Some(c) => c.open_reads,
+        };
+        if access == Access::Read && open_reads {
+            return Ok(());
+        }
+        let t = self.clock.now();
+        st.sessions.retain(|(_, expiry)| *expiry > t);
+        match (bearer(rq), session_cookie(rq)) {
+            (Some(password), _) => {
+                st.locked(t)?;
+                let ok = self.verify(&mut st, password);
+                st.attempt(ok, t)
+            },
+            (None, Some(token)) if st.sessions.iter().any(|(s, _)| constant_time_eq(s.as_bytes(), token.as_bytes())) => Ok(()),
+            (None, _) => Err(Denied::Unauthorized),
+        }
+    }
+
+    /// start a session for a correct password. Returns the cookie value
+    pub fn login(&self, password : &str) -> Result<String, Denied> {
+        let mut st = self.state.lock().unwrap();
+        let t = self.clock.now();
+        st.locked(t)?;
+        let ok = st.credentials.is_none() || self.verify(&mut st, password);
+        st.attempt(ok, t)?;
+
+        let mut bytes = [0u8; 16];
+        getrandom::getrandom(&mut bytes).map_err(|_| Denied::Unauthorized)?;
+        let token : String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        if st.sessions.len() >= MAX_SESSIONS {
+            st.sessions.remove(0);
+        }
+        st.sessions.push((token.clone(), t + SESSION_SECONDS));
+        Ok(token)
+    }
+
+    /// whether `password` is the provisioned one. A match against a hash of older
+    /// firmware is saved again with [ROUNDS]
+    fn verify(&self, st : &mut AuthState, password : &str) -> bool {
+        let c = match &st.credentials {
+            Some(c) => c,
+            None => return false,
+        };
+        let ok = c.matches(password);
+        if ok && c.rounds < ROUNDS {
+            let open_reads = c.open_reads;
+            match self.save(password, open_reads) {
+                Ok(c) => st.credentials = Some(c),
+                Err(e) => log::warn!("rehashing the password: {:?}", e),
+            }
+        }
+        ok
+    }
+
+    fn save(&self, password : &str, open_reads : bool) -> anyhow::Result<Credentials> {
+        let credentials = Credentials::new(password, open_reads)?;
+        store::save(&mut *self.nvs.lock().unwrap(), CREDENTIALS, &credentials)?;
+        Ok(credentials)
+    }
+
+    /// The first password, set when the firmware is built rather than by whoever reaches
+    /// the device first. False if there is one already.
+    pub fn provision(&self, password : &str, open_reads : bool) -> anyhow::Result<bool> {
+        if password.is_empty() {
+            anyhow::bail!("empty password");
+        }
+        let mut st = self.state.lock().unwrap();
+        if st.credentials.is_some() {
+            return Ok(false);
+        }
+        st.credentials = Some(self.save(password, open_reads)?);
+        Ok(true)
+    }
+
+    pub fn provisioned(&self) -> bool {
+        self.state.lock().unwrap().credentials.is_some()
+    }
+
+    /// replace the password and forget all sessions. The caller must already have
+    /// passed [Auth::check], the first password comes from [Auth::provision]
+    pub fn set_password(&self, password : &str, open_reads : bool) -> Result<(), ApiError> {
+        if password.is_empty() {
+            return Err(ValidationErrors::field("password", "empty").into());
+        }
+        let mut st = self.state.lock().unwrap();
+        if st.credentials.is_none() {
+            return Err(Denied::Unprovisioned.into());
+        }
+        st.credentials = Some(self.save(password, open_reads)?);
+        st.sessions.clear();
+        Ok(())
+    }
+}
+
+impl AuthState {
+    fn locked(&self, t : i64) -> Result<(), Denied> {
+        if self.locked_until > t { Err(Denied::RateLimited(self.locked_until - t)) } else { Ok(()) }
+    }
+
+    /// count wrong passwords and lock out for 2, 4, 8 ... seconds after [FREE_FAILURES]
+    fn attempt(&mut self, ok : bool, t : i64) -> Result<(), Denied> {
+        if ok {
+            self.failures = 0;
+            return Ok(());
+        }
+        self.failures += 1;
+        if self.failures > FREE_FAILURES {
+            let exponent = (self.failures - FREE_FAILURES).min(16);
+            self.locked_until = t + (1i64 << exponent).min(MAX_LOCKOUT_SECONDS);
+        }
+        Err(Denied::Unauthorized)
+    }
+}
+
+fn bearer(rq : &HttpRequest) -> Option<&str> {
+    rq.header("Authorization")?.strip_prefix("Bearer ")
+}
+
+fn session_cookie(rq : &HttpRequest) -> Option<&str> {
+    rq.header("Cookie")?
+        .split(';')
+        .find_map(|kv| kv.trim().strip_prefix("session="))
+}
+
+/// value for the Set-Cookie header
+pub fn cookie(token : &str) -> String {
+    format!("session={}; Max-Age={}; HttpOnly; SameSite=Strict", token, SESSION_SECONDS)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicI64, Ordering};
+
+    use super::*;
+    use crate::store::MemoryStore;
+
+    struct TestClock(AtomicI64);
+
+    impl Clock for TestClock {
+        fn now(&self) -> i64 {
+            self.0.load(Ordering::SeqCst)
+        }
+        fn delay_ms(&self, ms : u32) {
+            self.0.fetch_add(ms as i64 / 1000, Ordering::SeqCst);
+        }
+    }
+
+    fn auth(open_reads : bool) -> (Auth, Arc<TestClock>) {
+        let clock = Arc::new(TestClock(AtomicI64::new(1_700_000_000)));
+        let auth = Auth::load(Arc::new(Mutex::new(MemoryStore::default()))).unwrap().with_clock(clock.clone());
+        assert!(auth.provision("hunter2", open_reads).unwrap());
+        (auth, clock)
+    }
+
+    fn request(headers : &[(&'static str, &str)]) -> HttpRequest {
+        HttpRequest { headers : headers.iter().map(|&(k, v)| (k, v.to_string())).collect(), body : Vec::new() }
+    }
+
+    fn bearer(password : &str) -> HttpRequest {
+        request(&[("Authorization", &format!("Bearer {}", password))])
+    }
+
+    fn cookie(token : &str) -> HttpRequest {
+        request(&[("Cookie", &format!("theme=dark; session={}", token))])
+    }
+
+    #[test]
+    fn password_and_sessions() {
+        let (auth, clock) = auth(false);
+        assert!(auth.check(&request(&[]), Access::Open).is_ok());
+        assert!(matches!(auth.check(&request(&[]), Access::Read), Err(Denied::Unauthorized)));
+        assert!(auth.check(&bearer("hunter2"), Access::Write).is_ok());
+        assert!(auth.check(&bearer("hunter3"), Access::Write).is_err());
+
+        let token = auth.login("hunter2").unwrap();
+        assert!(auth.check(&cookie(&token), Access::Write).is_ok());
+        assert!(auth.check(&cookie("0123456789abcdef0123456789abcdef"), Access::Write).is_err());
+        clock.delay_ms(SESSION_SECONDS as u32 * 1000);
+        assert!(auth.check(&cookie(&token), Access::Write).is_err());
+
+        // a new password ends the sessions
+        let token = auth.login("hunter2").unwrap();
+        assert!(auth.set_password("correct horse", true).is_ok());
+        assert!(auth.check(&cookie(&token), Access::Write).is_err());
+        assert!(auth.check(&request(&[]), Access::Read).is_ok());
+        assert!(auth.check(&request(&[]), Access::Write).is_err());
+        assert!(auth.check(&bearer("correct horse"), Access::Write).is_ok());
+    }
+
+    #[test]
+    fn lockout_after_wrong_passwords() {
+        let (auth, clock) = auth(false);
+        let token = auth.login("hunter2").unwrap();
+        for _ in 0..FREE_FAILURES {
+            assert!(matches!(auth.login("guess"), Err(Denied::Unauthorized)));
+        }
+        assert!(matches!(auth.check(&bearer("guess"), Access::Write), Err(Denied::Unauthorized)));
+        // now even the right password waits, 2 s
+        assert!(matches!(auth.login("hunter2"), Err(Denied::RateLimited(2))));
+        assert!(matches!(auth.check(&bearer("hunter2"), Access::Write), Err(Denied::RateLimited(2))));
+        // but the session that was open stays open
+        assert!(auth.check(&cookie(&token), Access::Write).is_ok());
+
+        clock.delay_ms(2000);
+        assert!(matches!(auth.login("guess"), Err(Denied::Unauthorized)));
+        assert!(matches!(auth.login("hunter2"), Err(Denied::RateLimited(4))));
+        clock.delay_ms(4000);
+        assert!(auth.login("hunter2").is_ok());
+        // which starts counting again
+        for _ in 0..FREE_FAILURES {
+            assert!(matches!(auth.login("guess"), Err(Denied::Unauthorized)));
+        }
+        assert!(auth.login("hunter2").is_ok());
+
+        // the lockout is at most MAX_LOCKOUT_SECONDS
+        for _ in 0..20 {
+            clock.delay_ms(MAX_LOCKOUT_SECONDS as u32 * 1000);
+            let _ = auth.login("guess");
+        }
+        assert!(matches!(auth.login("hunter2"), Err(Denied::RateLimited(MAX_LOCKOUT_SECONDS))));
+    }
+
+    #[test]
+    fn stale_cookies_are_no_penalty() {
+        let (auth, _) = auth(false);
+        // the ui polling with a cookie from before a reboot
+        for _ in 0..100 {
+            assert!(matches!(auth.check(&cookie("from_before_the_reboot"), Access::Read), Err(Denied::Unauthorized)));
+        }
+        assert!(auth.login("hunter2").is_ok());
+    }
+
+    #[test]
+    fn provisioned_once() {
+        let nvs : Arc<Mutex<dyn BlobStore + Send>> = Arc::new(Mutex::new(MemoryStore::default()));
+        let fresh = Auth::load(nvs.clone()).unwrap();
+        // nobody can claim an unprovisioned device over the network
+        assert!(!fresh.provisioned());
+        assert!(matches!(fresh.set_password("mine now", true), Err(ApiError::Denied(Denied::Unprovisioned))));
+        assert!(fresh.provision("", true).is_err());
+        assert!(fresh.provision("hunter2", true).unwrap());
+
+        // the next boot provisions again, which keeps the password that was changed since
+        let auth = Auth::load(nvs).unwrap();
+        assert!(!auth.provision("hunter2", true).unwrap());
+        assert!(auth.set_password("correct horse", true).is_ok());
+        assert!(!auth.provision("hunter2", true).unwrap());
+        assert!(auth.login("correct horse").is_ok());
+        assert!(auth.login("hunter2").is_err());
+        assert!(matches!(auth.set_password("", true), Err(ApiError::BadRequest(_))));
+    }
+
+    #[test]
+    fn pbkdf2_sha256() {
+        // RFC 7914 section 11 and the usual PBKDF2-HMAC-SHA256 vectors
+        let hex = |h : [u8; 32]| h.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+        assert_eq!(hex(hash(b"salt", "password", 1)), "120fb6cffcf8b32c43e7225256c4f837a86548c92ccc35480805987cb70be17b");
+        assert_eq!(hex(hash(b"salt", "password", 4096)), "c5e478d59288c841aa530db6845c4c8d962893a001ce4e11a4963873aa98134a");
+        let c = Credentials::new("hunter2", false).unwrap();
+        assert_eq!(c.rounds, ROUNDS);
+        assert!(c.matches("hunter2") && !c.matches("hunter3"));
+    }
+
+    #[test]
+    fn older_hash_is_upgraded() {
+        let nvs : Arc<Mutex<dyn BlobStore + Send>> = Arc::new(Mutex::new(MemoryStore::default()));
+        // as older firmware saved them, without `rounds`
+        #[derive(Serialize)]
+        struct Old {
+            salt : [u8; 16],
+            hash : [u8; 32],
+            open_reads : bool,
+        }
+        let salt = [7u8; 16];
+        let mut h = Sha256::new();
+        h.update(salt);
+        h.update(b"hunter2");
+        let old = Old { salt, hash : h.finalize().into(), open_reads : true };
+        store::save(&mut *nvs.lock().unwrap(), CREDENTIALS, &old).unwrap();
+
+        let auth = Auth::load(nvs.clone()).unwrap();
+        assert!(auth.login("hunter3").is_err());
+        assert!(auth.login("hunter2").is_ok());
+        let saved : Credentials = store::load(&*nvs.lock().unwrap(), CREDENTIALS).unwrap().unwrap();
+        assert!(saved.rounds == ROUNDS && saved.open_reads && saved.matches("hunter2"));
+        assert!(Auth::load(nvs).unwrap().login("hunter2").is_ok());
+    }
+}
@@ -0,0 +1,214 @@
+//! The http api on a laptop, for working on www/ without a board:
+//!
+//! `cargo run --no-default-features --features host --bin host --target x86_64-unknown-linux-gnu -- [--port 8080] [--speedup 60] [--replay measurement.csv] [--data dir] [--faults error=0.05,nan=0.01,write_error=0.1] [--password pw]`
+//!
+//! Without `--replay` the sensors are the simulated dehydrator in sim.rs, running `--speedup`
+//! times faster than real time. With it the rows of a downloaded `/measurement.csv` are
+//! played back in a loop. The namespaces are kept in memory and forgotten on exit, or with
+//! `--data` in one subdirectory each. `--faults` makes the sensors and the measurement store
+//! misbehave as described in fault.rs. `--password` provisions one like `DEHYDRATOR_PASSWORD`
+//! does for the firmware, without it every endpoint is open.
+
+use std::{sync::{Arc, Mutex}, thread, io::Read};
+
+use anyhow::{anyhow, Context};
+
+use dehydrator::{*, api::Shared, auth::Auth, hal::{ClimatePair, Clock, SystemClock}, host::{VirtualDial, LogShutdown},
+                 linearly_calibrated::CalibratedSensor, meas::Meas, replay::Replay, router::{Router, HttpRequest, HttpResponse, Body, HEADERS, MAX_BODY},
+                 sim::{Sim, Pace, PlantParams}, store::{BlobStore, MemoryStore, DirStore}, fault::{Faults, Faulty}};
+
+struct Args {
+    port : u16,
+    speedup : f64,
+    replay : Option<String>,
+    data : Option<String>,
+    faults : Faults,
+    password : Option<String>,
+}
+
+fn args() -> anyhow::Result<Args> {
+    let mut args = Args { port : 8080, speedup : 60.0, replay : None, data : None, faults : Faults::default(), password : None };
+    let mut it = std::env::args().skip(1);
+    while let Some(a) = it.next() {
+        let mut value = || it.next().ok_or_else(|| anyhow!("{} needs a value", a));
+        match a.as_str() {
+            "--port" => args.port = value()?.parse()?,
+            "--speedup" => args.speedup = value()?.parse()?,
+            "--replay" => args.replay = Some(value()?),
+            "--data" => args.data = Some(value()?),
+            "--faults" => args.faults = value()?.parse()?,
+            "--password" => args.password = Some(value()?),
+            _ => return Err(anyhow!("unknown argument {}", a)),
+        }
+    }
+    Ok(args)
+}
+
+/// a namespace in memory or in `data/namespace`
+fn namespace(data : &Option<String>, namespace : &str) -> anyhow::Result<Arc<Mutex<dyn BlobStore + Send>>> {
+    Ok(match data {
+        Some(dir) => Arc::new(Mutex::new(DirStore::new(std::path::Path::new(dir).join(namespace))?)),
+        None => Arc::new(Mutex::new(MemoryStore::default())),
+    })
+}
+
+/// `--faults` with a different seed for each wrapper, so they don't fail in step
+fn faulty(args : &Args, n : u64) -> Faults {
+    Faults { seed : args.faults.seed + n, ..args.faults.clone() }
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = args()?;
+    let calib = namespace(&args.data, "calib")?;
+    let comp : Arc<Mutex<dyn BlobStore + Send>> = match &args.data {
+        Some(dir) => Arc::new(Mutex::new(Faulty::new(DirStore::new(std::path::Path::new(dir).join("comp"))?, faulty(&args, 0)))),
+        None => Arc::new(Mutex::new(Faulty::new(MemoryStore::default(), faulty(&args, 0)))),
+    };
+    let auth = Arc::new(Auth::load(namespace(&args.data, "auth")?)?);
+    if let Some(password) = &args.password {
+        auth.provision(password, false)?;
+    }
+
+    let (shared, measure_loop) : (Shared, Box<dyn FnOnce(Shared) + Send>) = match &args.replay {
+        Some(path) => {
+            let file = std::fs::File::open(path).with_context(|| format!("opening {}", path))?;
+            let replay = Replay::new(replay::read_csv(std::io::BufReader::new(file))?)?;
+            let shared = shared(calib.clone(), comp.clone(), auth.clone(),
+                                CalibratedSensor::new(Faulty::new(replay.amps(), faulty(&args, 1)), calib.clone(), "ACS712".to_string()),
+                                CalibratedSensor::new(Faulty::new(replay.grams(), faulty(&args, 2)), calib.clone(), "HX711".to_string()),
+                                Arc::new(Mutex::new(VirtualDial::new(api::stepper_len(&calib)))),
+                                Arc::new(Mutex::new(LogShutdown::default())),
+                                Arc::new(SystemClock));
+            let climate = Faulty::new(replay, faulty(&args, 3));
+            (shared, Box::new(move |shared| measure(climate, shared, SystemClock)))
+        },
+        None => {
+            let sim = Sim::new(PlantParams::default(), Pace::Accelerated(args.speedup));
+            let shared = shared(calib.clone(), comp.clone(), auth.clone(),
+                                CalibratedSensor::new(Faulty::new(sim.current(), faulty(&args, 1)), calib.clone(), "ACS712".to_string()),
+                                CalibratedSensor::new(Faulty::new(sim.scale(), faulty(&args, 2)), calib.clone(), "HX711".to_string()),
+                                Arc::new(Mutex::new(sim.clone())),
+                                Arc::new(Mutex::new(sim.clone())),
+                                Arc::new(sim.clone()));
+            let climate = Faulty::new(sim.clone(), faulty(&args, 3));
+            (shared, Box::new(move |shared| measure(climate, shared, sim)))
+        },
+    };
+
+    let mut router = Router::new(auth);
+    router.assets(assets::ASSETS);
+    api::register(&mut router, &shared);
+
+    let shared1 = shared.clone();
+    thread::spawn(move || {
+        control::run_profile(&*shared1.config,
+                             &*shared1.step_index_completed,
+                             &*shared1.manual_stepper,
+                             &*shared1.dial,
+                             &*shared1.clock)
+    });
+    thread::spawn(move || measure_loop(shared));
+
+    serve(router, args.port)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn shared(calib : Arc<Mutex<dyn BlobStore + Send>>,
+          comp : Arc<Mutex<dyn BlobStore + Send>>,
+          auth : Arc<Auth>,
+          amps : CalibratedSensor<'static>,
+          grams : CalibratedSensor<'static>,
+          dial : Arc<Mutex<dyn hal::Dial + Send>>,
+          shutdown : Arc<Mutex<dyn hal::Shutdown + Send>>,
+          clock : Arc<dyn Clock + Send + Sync>) -> Shared {
+    let mut sensors = [amps, grams];
+    linearly_calibrated::load_calibrations(&mut sensors);
+    Shared {
+        config : Arc::new(Mutex::new(Config::initial(clock.now()))),
+        step_index_completed : Arc::new(Mutex::new(0)),
+        manual_stepper : Arc::new(Mutex::new(false)),
+        dial,
+        shutdown,
+        calibrated_sensors : Arc::new(Mutex::new(sensors)),
+        calib,
+        comp,
+        auth,
+        status : Arc::new(Mutex::new(Status::default())),
+        clock,
+        run : Arc::new(Mutex::new(Default::default())),
+    }
+}
+
+/// the measurement loop of main.rs, logging errors instead of restarting
+fn measure(mut climate : impl ClimatePair, shared : Shared, clock : impl Clock) {
+    let mut meas = Meas::new();
+    let mut run = 0;
+    let mut dry = false;
+    let mut reached = Vec::new();
+    let mut weigher = weigh::Weigher::new(Default::default());
+    // no food probes on the host
+    let mut probes : Vec<Box<dyn hal::Probes + Send>> = Vec::new();
+    let mut j = match control::last_key(&*shared.comp) {
+        Ok(j) => j,
+        Err(e) => return eprintln!("measurement: {:?}", e),
+    };
+    loop {
+        if control::follow_run(&shared.run, &mut run, &mut dry) {
+            reached.clear();
+        }
+        let r = control::sample_blob(&mut meas, &mut climate, &shared.calibrated_sensors, &mut weigher, &mut probes, &shared.config, &shared.status, &clock)
+            .map(|()| control::track_probes(&meas, &shared.config, &mut reached))
+            .and_then(|()| control::shutdown_if_dry(&meas, &reached, &shared.run, &*shared.shutdown, &shared.calibrated_sensors, &mut dry))
+            .and_then(|()| control::store_blob(&*shared.comp, &mut j, &meas));
+        if let Err(e) = r {
+            eprintln!("measurement: {:?}", e);
+        }
+    }
+}
+
+/// the host counterpart of esp_http.rs
+fn serve(router : Router, port : u16) -> anyhow::Result<()> {
+    let server = tiny_http::Server::http(("0.0.0.0", port)).map_err(|e| anyhow!("{}", e))?;
+    println!("listening on http://localhost:{}", port);
+    let router = Arc::new(router);
+    for mut rq in server.incoming_requests() {
+        let router = router.clone();
+        thread::spawn(move || {
+            let headers = HEADERS.iter()
+                .filter_map(|&name| rq.headers().iter()
+                    .find(|h| h.field.equiv(name))
+                    .map(|h| (name, h.value.as_str().to_string())))
+                .collect();
+            // one byte more than allowed so that read_json can tell it was too long
+            let mut body = Vec::new();
+            if let Err(e) = rq.as_reader().take(MAX_BODY as u64 + 1).read_to_end(&mut body) {
+                eprintln!("{}: {}", rq.url(), e);
+                return;
+            }
+
+            let rsp = match router.find(rq.method().as_str(), rq.url()) {
+                Some(handle) => handle(&HttpRequest { headers, body }),
+                None => HttpResponse::new(404),
+            };
+            let mut data = Vec::new();
+            match rsp.body {
+                Body::Empty => (),
+                Body::Bytes(b) => data = b,
+                Body::Static(b) => data = b.to_vec(),
+                Body::Stream(f) => if let Err(e) = f(&mut data) {
+                    eprintln!("{}: {:?}", rq.url(), e);
+                },
+            }
+            let mut response = tiny_http::Response::from_data(data).with_status_code(tiny_http::StatusCode(rsp.status));
+            for (k, v) in &rsp.headers {
+                if let Ok(h) = tiny_http::Header::from_bytes(k.as_bytes(), v.as_bytes()) {
+                    response.add_header(h);
+                }
+            }
+            if let Err(e) = rq.respond(response) {
+                eprintln!("responding: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
@@ -0,0 +1,61 @@
+//! When would past runs have stopped with other settings?
+//!
+//! `cargo run --no-default-features --features host --bin replay --target x86_64-unknown-linux-gnu -- [--w-cut 10,12,14] [--window 1,10,50] measurement.csv...`
+//!
+//! Every combination of `w_cut` (g/m³) and smoothing window (measurements) is run through
+//! the cutoff and shutdown logic of control.rs. A file may hold several runs, split as
+//! [replay::runs] does. The report is csv on stdout, one line per run and combination.
+//! `stop_time` is empty when the run never got dry enough.
+
+use anyhow::{anyhow, Context};
+
+use dehydrator::replay;
+
+struct Args {
+    w_cuts : Vec<f32>,
+    windows : Vec<usize>,
+    files : Vec<String>,
+}
+
+fn list<T : std::str::FromStr>(s : &str) -> anyhow::Result<Vec<T>> where T::Err : std::error::Error + Send + Sync + 'static {
+    s.split(',').map(|x| x.trim().parse::<T>().with_context(|| format!("in {}", s))).collect()
+}
+
+fn args() -> anyhow::Result<Args> {
+    let mut args = Args { w_cuts : vec![12.0], windows : vec![1], files : Vec::new() };
+    let mut it = std::env::args().skip(1);
+    while let Some(a) = it.next() {
+        let mut value = || it.next().ok_or_else(|| anyhow!("{} needs a value", a));
+        match a.as_str() {
+            "--w-cut" => args.w_cuts = list(&value()?)?,
+            "--window" => args.windows = list(&value()?)?,
+            _ => args.files.push(a),
+        }
+    }
+    if args.files.is_empty() {
+        return Err(anyhow!("no csv files given"));
+    }
+    Ok(args)
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = args()?;
+    println!("file,run,w_cut,window,stop_j,stop_time,elapsed_hours,grams_at_stop,final_grams");
+    for file in &args.files {
+        let f = std::fs::File::open(file).with_context(|| format!("opening {}", file))?;
+        let rows = replay::read_csv(std::io::BufReader::new(f)).with_context(|| format!("reading {}", file))?;
+        for (k, run) in replay::runs(&rows).into_iter().enumerate() {
+            let final_grams = run.last().map_or(f32::NAN, |r| r.grams);
+            for &w_cut in &args.w_cuts {
+                for &window in &args.windows {
+                    match replay::stop(run, w_cut, window) {
+                        Some(s) => println!("{},{},{},{},{},{},{:.2},{},{}", file, k + 1, w_cut, window,
+                                            s.j, s.time, s.elapsed as f32 / 3600.0, s.grams, final_grams),
+                        None => println!("{},{},{},{},,,,,{}", file, k + 1, w_cut, window, final_grams),
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
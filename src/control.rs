@@ -0,0 +1,368 @@
+use std::sync::Mutex;
+
+use log::*;
+
+use crate::{Config, Role, SensorStatus, Status, Uniformity, hal::{self, Channel, ClimatePair, Probes, Clock, Dial, Shutdown}, meas::{self, Meas, Power, SensorChannel, N1}, psychro, linearly_calibrated::CalibratedSensor,
+            nvs::{self, Key}, store::{self, BlobStore}, weigh::Weigher};
+
+impl Config {
+    /// until the web interface posts a profile
+    pub fn initial(now : i64) -> Self {
+        Config {
+            step_times : [0;20],
+            step_fracs : [0.0;20],
+            measurement_period_ms : 2000,
+            n_wavelets: 40,
+            w_cut: 12.0,
+            probe_target: 0.0,
+            pressure_kpa: 0.0,
+            zero_scale_at_start: false,
+            last_modified: now,
+        }
+    }
+}
+
+/// One pass of the profile thread: moves the dial following the piecewise constant function
+/// specified by step_fracs and step_times.
+///
+/// step_index_completed is for getting how far the stepper has moved
+/// into the http thread. And it is for the http thread to reset the stepper
+/// when the user requests it directly or indirectly (by changing the
+/// temperature profile).
+/// Assuming the stepper thread has run, this is true:
+/// > let i = step_index_completed.lock().unwrap();
+/// > time >= config.step_times[i]
+/// > stepper.set_fraction(config.lock().unwrap().step_fracs[i]) // stepper doesn't move
+///
+/// Nothing moves while `manual` is set by the stepper endpoints.
+pub fn follow_profile<D : Dial + ?Sized>(config : &Mutex<Config>,
+                                         step_index_completed : &Mutex<usize>,
+                                         manual : &Mutex<bool>,
+                                         dial : &Mutex<D>,
+                                         now : i64) -> anyhow::Result<()> {
+    let config = config.lock().unwrap();
+    if *manual.lock().unwrap() {
+        return Ok(());
+    }
+    let mut i_min = step_index_completed.lock().unwrap();
+    if *i_min == 0 {
+        dial.lock().unwrap().set_fraction(config.step_fracs[0])?;
+        *i_min += 1;
+    }
+    let t = now - config.last_modified;
+    for i in *i_min..config.step_times.len() {
+        if config.step_times[i] > t {
+            dial.lock().unwrap().set_fraction(config.step_fracs[i])?;
+            *i_min = (i+1).min(config.step_times.len());
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// the profile thread: [follow_profile] every second
+pub fn run_profile<D : Dial + ?Sized>(config : &Mutex<Config>,
+                                      step_index_completed : &Mutex<usize>,
+                                      manual : &Mutex<bool>,
+                                      dial : &Mutex<D>,
+                                      clock : &(impl Clock + ?Sized)) -> ! {
+    loop {
+        clock.delay_ms(1000); // configurable?
+        if let Err(e) = follow_profile(config, step_index_completed, manual, dial, clock.now()) {
+            warn!("profile: {:?}", e);
+        }
+    }
+}
+
+/// Fill `meas` with [N1] measurements `measurement_period_ms` apart and count how
+/// many are below the humidity cutoff. The sensors are `[current, scale]`, the scale
+/// read through `weigher`. `status` follows every sample.
+#[allow(clippy::too_many_arguments)]
+pub fn sample_blob(meas : &mut Meas<[f32; N1]>,
+                   climate : &mut impl ClimatePair,
+                   sensors : &Mutex<[CalibratedSensor; 2]>,
+                   weigher : &mut Weigher,
+                   probes : &mut (impl Probes + ?Sized),
+                   config : &Mutex<Config>,
+                   status : &Mutex<Status>,
+                   clock : &(impl Clock + ?Sized)) -> anyhow::Result<()> {
+    // probes and sensors that stop answering stay in the blob as NaN, and so do the
+    // current and weight samples that can't be read
+    for p in meas.probes.iter_mut() {
+        *p = [f32::NAN; N1];
+    }
+    for c in meas.sensors.iter_mut() {
+        c.temperature = [f32::NAN; N1];
+        c.humidity = [f32::NAN; N1];
+    }
+    if meas.power.is_some() {
+        meas.power = Some(Power::new());
+    }
+    // get N1 measurements
+    for i in 0..N1 {
+        let (inside, outside) = climate.read().unwrap_or_else(|e| {
+            warn!("climate: {:?}", e);
+            let missing = hal::Climate { temperature : f32::NAN, humidity : f32::NAN };
+            (missing, missing)
+        });
+        let heating = climate.heating();
+        let t = probes.read().unwrap_or_else(|e| {
+            warn!("probes: {:?}", e);
+            Vec::new()
+        });
+        if t.len() > meas.probes.len() {
+            meas.probes.resize(t.len(), [f32::NAN; N1]);
+        }
+        for (p, &x) in meas.probes.iter_mut().zip(&t) {
+            p[i] = x;
+        }
+        let channels = climate.channels();
+        for ch in &channels {
+            let k = match meas.sensors.iter().position(|c| c.name == ch.name) {
+                Some(k) => k,
+                None => {
+                    meas.sensors.push(SensorChannel { name : ch.name.clone(), role : ch.role,
+                                                      temperature : [f32::NAN; N1], humidity : [f32::NAN; N1] });
+                    meas.sensors.len() - 1
+                },
+            };
+            meas.sensors[k].temperature[i] = ch.climate.temperature;
+            meas.sensors[k].humidity[i] = ch.climate.humidity;
+        }
+        clock.delay_ms(config.lock().unwrap().measurement_period_ms);
+
+        // copy into Meas
+        meas.inside_temp[i] = inside.temperature;
+        meas.outside_temp[i] = outside.temperature;
+        meas.inside_rh[i] = inside.humidity;
+        meas.outside_rh[i] = outside.humidity;
+        let power = {
+            let mut calib = sensors.lock().unwrap();
+            meas.amps[i] = calib[0].read().unwrap_or_else(|e| {
+                warn!("{}: {:?}", calib[0].name, e);
+                f32::NAN
+            });
+            if let Err(e) = calib[0].follow_zero(meas.amps[i], clock.now()) {
+                warn!("auto zero: {:?}", e);
+            }
+            meas.peak_amps[i] = calib[0].peak().unwrap_or(f32::NAN);
+            meas.grams[i] = weigher.read(&mut calib[1]).unwrap_or_else(|e| {
+                warn!("scale: {:?}", e);
+                weigher.stable = false;
+                f32::NAN
+            });
+            meas.stable[i] = weigher.stable as u8 as f32;
+            calib[0].power()
+        };
+        meas.heater[i] = heating[0] as u8 as f32 + 2.0 * heating[1] as u8 as f32;
+        if let Some(r) = &power {
+            let p = meas.power.get_or_insert_with(Power::new);
+            p.volts[i] = r.volts;
+            p.watts[i] = r.watts;
+            p.energy_wh[i] = r.energy_wh;
+            p.hz[i] = r.hz;
+            p.power_factor[i] = r.power_factor;
+        }
+
+        let mut s = status.lock().unwrap();
+        s.time = clock.now();
+        s.inside_temp = inside.temperature;
+        s.inside_rh = inside.humidity;
+        s.outside_temp = outside.temperature;
+        s.outside_rh = outside.humidity;
+        s.amps = meas.amps[i];
+        s.amps_peak = meas.peak_amps[i];
+        s.power = power;
+        s.grams = meas.grams[i];
+        s.stable = weigher.stable;
+        s.heater = heating;
+        s.probes = t;
+        s.uniformity = uniformity(&channels);
+        let pressure = config.lock().unwrap().pressure_kpa;
+        s.psychrometrics = [inside, outside].map(|c| psychro::psychrometrics(c.temperature, c.humidity, pressure));
+        s.sensors = channels.into_iter()
+            .map(|c| SensorStatus { name : c.name, role : c.role, temperature : c.climate.temperature,
+                                    humidity : c.climate.humidity, heater : c.heating })
+            .collect();
+    }
+    meas.cutoffs = count_cutoffs(&meas.inside_temp, &meas.inside_rh, config.lock().unwrap().w_cut, 1);
+    // a missing sample is NaN rather than a lost blob, see crate::sht
+    meas.climate_errors = (0..N1)
+        .filter(|&i| [meas.inside_temp[i], meas.inside_rh[i], meas.outside_temp[i], meas.outside_rh[i]].iter().any(|x| x.is_nan()))
+        .count() as i32;
+    meas.sensor_errors = (0..N1)
+        .filter(|&i| meas.amps[i].is_nan() || meas.grams[i].is_nan())
+        .count() as i32;
+    // a status register that can't be read doesn't lose the blob
+    meas.status = climate.status().unwrap_or_else(|e| {
+        warn!("sht31 status: {:?}", e);
+        [None, None]
+    });
+    {
+        let mut s = status.lock().unwrap();
+        s.alerts = meas.status.map(|r| r.map_or(Vec::new(), hal::sht_flags));
+        s.climate_errors = meas.climate_errors;
+        s.sensor_errors = meas.sensor_errors;
+    }
+    meas.pressure_kpa = config.lock().unwrap().pressure_kpa;
+    meas.time = clock.now();
+    Ok(())
+}
+
+/// the key before the first one [store_blob] should write
+pub fn last_key(comp : &Mutex<impl BlobStore + ?Sized>) -> anyhow::Result<Key> {
+    let mut j = nvs::next_key(&nvs::comp_keys(&*comp.lock().unwrap())?);
+    j.prev();
+    Ok(j)
+}
+
+/// compress `meas` into the blob after `j`
+pub fn store_blob(comp : &Mutex<impl BlobStore + ?Sized>, j : &mut Key, meas : &Meas<[f32; N1]>) -> anyhow::Result<()> {
+    j.next();
+    store::save(&mut *comp.lock().unwrap(), &j.to_string(), &meas::compress(meas))
+}
+
+/// How many of the measurements have an inside absolute humidity below `w_cut`, after a
+/// trailing mean over `window` measurements (1 is no smoothing). The mean starts over with
+/// every blob.
+pub fn count_cutoffs(inside_temp : &[f32], inside_rh : &[f32], w_cut : f32, window : usize) -> i32 {
+    let w : Vec<f32> = inside_temp.iter().zip(inside_rh)
+        .map(|(&t, &rh)| abs_humidity_g_per_m3(t, rh))
+        .collect();
+    let window = window.max(1);
+    (0..w.len())
+        .filter(|&i| {
+            let recent = &w[(i + 1).saturating_sub(window)..=i];
+            recent.iter().sum::<f32>() / (recent.len() as f32) < w_cut
+        })
+        .count() as i32
+}
+
+/// the food is done when the whole blob of `n` measurements was below `w_cut`
+pub fn dry_enough(cutoffs : i32, n : usize) -> bool {
+    cutoffs as usize == n
+}
+
+/// Note which food probes have read at least the configured `probe_target` °C in `meas`.
+/// A target of 0 doesn't wait for the probes.
+pub fn track_probes(meas : &Meas<[f32; N1]>, config : &Mutex<Config>, reached : &mut Vec<bool>) {
+    let target = config.lock().unwrap().probe_target;
+    if reached.len() < meas.probes.len() {
+        reached.resize(meas.probes.len(), false);
+    }
+    for (r, p) in reached.iter_mut().zip(&meas.probes) {
+        *r |= target <= 0.0 || p.iter().any(|&t| t >= target);
+    }
+}
+
+/// Turn the dehydrator off after the first blob that is [dry_enough], once every probe has
+/// reached the target ([track_probes]). A probe that fell out before reaching it (NaN) holds
+/// the shutdown back, since the food can't be shown to be safe. `done` remembers that it
+/// happened so the ir signal isn't repeated every blob. Only a [Run] that is going is shut
+/// down: room air at boot is dry too, and after `POST /shutdown` the dehydrator is off already.
+pub fn shutdown_if_dry(meas : &Meas<[f32; N1]>,
+                       reached : &[bool],
+                       run : &Mutex<Run>,
+                       shutdown : &Mutex<impl Shutdown + ?Sized>,
+                       sensors : &Mutex<[CalibratedSensor; 2]>,
+                       done : &mut bool) -> anyhow::Result<()> {
+    if !run.lock().unwrap().going {
+        return Ok(());
+    }
+    let dry = dry_enough(meas.cutoffs, N1);
+    if !*done && dry && !reached.iter().all(|&r| r) {
+        info!("dry at {} but probes {:?} haven't reached the target", meas.time, reached);
+    } else if !*done && dry {
+        info!("dry at {}, shutting down", meas.time);
+        shut_down(shutdown, sensors)?;
+        *done = true;
+    }
+    Ok(())
+}
+
+/// the IR shutdown, after which nothing draws current, so the current sensor zeroes at its
+/// next read ([CalibratedSensor::shut_down])
+pub fn shut_down(shutdown : &Mutex<impl Shutdown + ?Sized>, sensors : &Mutex<[CalibratedSensor; 2]>) -> anyhow::Result<()> {
+    shutdown.lock().unwrap().shutdown()?;
+    sensors.lock().unwrap()[0].shut_down();
+    Ok(())
+}
+
+/// The runs since boot. `POST /restart` starts one, and so does a posted config when none
+/// is going. The measurement loop ends it after the dry shutdown, see [follow_run].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Run {
+    /// 0 until the first run
+    pub number : u32,
+    pub going : bool,
+}
+
+impl Run {
+    pub fn start(&mut self) {
+        self.number += 1;
+        self.going = true;
+    }
+}
+
+/// Called by the measurement loop before each blob. `seen` is the last run it noticed and
+/// `done` is that of [shutdown_if_dry]. A new run clears `done` and gives true, so that the
+/// loop forgets the rest of the last run too. A run that is `done` stops going.
+pub fn follow_run(run : &Mutex<Run>, seen : &mut u32, done : &mut bool) -> bool {
+    let mut run = run.lock().unwrap();
+    let new = run.number != *seen;
+    if new {
+        *seen = run.number;
+        *done = false;
+    }
+    if *done {
+        run.going = false;
+    }
+    new
+}
+
+/// How far apart the tray sensors that answered are, None with fewer than two of them.
+/// A sensor in the heater's cooldown is left out since it reads warm and dry.
+pub fn uniformity(channels : &[Channel]) -> Option<Uniformity> {
+    let trays : Vec<_> = channels.iter()
+        .filter(|c| matches!(c.role, Role::Tray(_)) && !c.heating)
+        .map(|c| c.climate)
+        .filter(|c| c.temperature.is_finite() && c.humidity.is_finite())
+        .collect();
+    if trays.len() < 2 {
+        return None;
+    }
+    let range = |x : &mut dyn Iterator<Item = f32>| {
+        let (lo, hi) = x.fold((f32::INFINITY, f32::NEG_INFINITY), |(lo, hi), v| (lo.min(v), hi.max(v)));
+        hi - lo
+    };
+    let n = trays.len() as f32;
+    let mean_t = trays.iter().map(|c| c.temperature).sum::<f32>() / n;
+    let var_t = trays.iter().map(|c| (c.temperature - mean_t).powi(2)).sum::<f32>() / n;
+    Some(Uniformity {
+        trays : trays.len() as u8,
+        temp_range : range(&mut trays.iter().map(|c| c.temperature)),
+        temp_sd : var_t.sqrt(),
+        rh_range : range(&mut trays.iter().map(|c| c.humidity)),
+        abs_humidity_range : range(&mut trays.iter().map(|c| abs_humidity_g_per_m3(c.temperature, c.humidity))),
+    })
+}
+
+/// absolute humidity in g/m3 according to
+/// <https://webbook.nist.gov/cgi/cbook.cgi?ID=C7732185&Mask=4&Type=ANTOINE&Plot=on#ANTOINE>
+/// temp should be between -17 and 100°C, rh_percent is 0 to 100
+pub fn abs_humidity_g_per_m3(temp_celsius : f32, rh_percent : f32) -> f32 {
+    // Stull 1947 antione equation for water vapor pressure from NIST
+    const A : f32 = 4.6543;
+    const B : f32 = 1435.264;
+    const C : f32 = -64.848;
+    const MW : f32 = 18.01528; // g/mol
+    const R : f32 = 8.31446261815324; // J/(mol K)
+    const PA_PER_BAR : f32 = 1e5;
+
+    let kelvin = temp_celsius + 273.15;
+    let bar_pw = 10f32.powf(A - B / (kelvin + C)) * rh_percent / 100.0;
+    let p = PA_PER_BAR * bar_pw;
+
+    // ideal gas law MW n/V = MW p/R/T
+    MW * p / kelvin / R
+}
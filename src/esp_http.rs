@@ -0,0 +1,66 @@
+use embedded_svc::{http::{Method, Headers}, io::{Read, Write}};
+use esp_idf_svc::http::server::{EspHttpServer, EspHttpConnection, Configuration};
+
+use crate::router::{Router, HttpRequest, Body, HEADERS, MAX_BODY};
+
+/// implement std::io::Write in terms of embedded_svc::io::blocking::Write
+/// for the csv stream
+struct WriteWrapper<'d, 'a> (embedded_svc::http::server::Response<&'d mut EspHttpConnection<'a>>);
+
+impl std::io::Write for WriteWrapper<'_, '_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.write(buf).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+    }
+}
+
+fn method(m : &str) -> Method {
+    match m {
+        "GET" => Method::Get,
+        "POST" => Method::Post,
+        "PUT" => Method::Put,
+        "DELETE" => Method::Delete,
+        m => panic!("unknown method {} in routes.rs", m),
+    }
+}
+
+/// start the ESP-IDF http server with one uri handler per route and per file of the web interface
+pub fn serve(router : Router) -> anyhow::Result<EspHttpServer> {
+    let handlers = router.into_handlers();
+    let mut http = EspHttpServer::new(&Configuration {
+        max_uri_handlers : handlers.len(),
+        ..Default::default()
+    })?;
+
+    for h in handlers {
+        let handle = h.handle;
+        http.fn_handler(&h.uri, method(h.method), move |mut rq| {
+            let headers = HEADERS.iter()
+                .filter_map(|&name| rq.header(name).map(|v| (name, v.to_string())))
+                .collect();
+
+            // one byte more than allowed so that read_json can tell it was too long
+            let mut body = Vec::new();
+            let mut buf = [0u8; 256];
+            while body.len() <= MAX_BODY {
+                let n = rq.read(&mut buf)?;
+                if n == 0 { break; }
+                body.extend_from_slice(&buf[..n.min(MAX_BODY + 1 - body.len())]);
+            }
+
+            let rsp = handle(&HttpRequest { headers, body });
+            let headers : Vec<(&str, &str)> = rsp.headers.iter().map(|(k, v)| (*k, v.as_str())).collect();
+            let mut w = rq.into_response(rsp.status, None, &headers)?;
+            match rsp.body {
+                Body::Empty => (),
+                Body::Bytes(b) => w.write_all(&b)?,
+                Body::Static(b) => w.write_all(b)?,
+                Body::Stream(f) => f(&mut WriteWrapper(w))?,
+            }
+            Ok(())
+        })?;
+    }
+    Ok(http)
+}
@@ -0,0 +1,185 @@
+//! Wrappers that make a sensor or a store misbehave on purpose: errors like an HX711 timeout
+//! or an SHT31 NACK, values that get stuck, NaN, slow reads and failed flash writes. The
+//! random choices come from a seeded generator so a failing run can be repeated.
+//!
+//! ```ignore
+//! let scale = Faulty::new(sim.scale(), "error=0.05,stuck=0.01,stuck_for=20".parse()?);
+//! ```
+
+use std::{str::FromStr, time::Duration};
+
+use anyhow::anyhow;
+
+use crate::{hal::{Channel, Climate, ClimatePair, ConvertedRead, Gain, CurrentSensor, Scale}, store::BlobStore};
+
+/// probabilities per call, all 0 by default
+#[derive(Clone, Debug, Default)]
+pub struct Faults {
+    /// a read returns an error
+    pub error : f32,
+    /// a read returns NaN
+    pub nan : f32,
+    /// the value gets stuck at the last one for `stuck_for` reads
+    pub stuck : f32,
+    pub stuck_for : u32,
+    /// a call first sleeps `latency_ms`
+    pub slow : f32,
+    pub latency_ms : u32,
+    /// set_blob or erase fail
+    pub write_error : f32,
+    pub seed : u64,
+}
+
+/// `error=0.05,nan=0.01,stuck=0.01,stuck_for=20,slow=0.1,latency_ms=500,write_error=0.1,seed=1`,
+/// any subset
+impl FromStr for Faults {
+    type Err = anyhow::Error;
+
+    fn from_str(s : &str) -> anyhow::Result<Self> {
+        let mut f = Faults { stuck_for : 10, latency_ms : 100, seed : 1, ..Default::default() };
+        for kv in s.split(',').filter(|kv| !kv.is_empty()) {
+            let (k, v) = kv.split_once('=').ok_or_else(|| anyhow!("{}: expected name=value", kv))?;
+            match k.trim() {
+                "error" => f.error = v.parse()?,
+                "nan" => f.nan = v.parse()?,
+                "stuck" => f.stuck = v.parse()?,
+                "stuck_for" => f.stuck_for = v.parse()?,
+                "slow" => f.slow = v.parse()?,
+                "latency_ms" => f.latency_ms = v.parse()?,
+                "write_error" => f.write_error = v.parse()?,
+                "seed" => f.seed = v.parse()?,
+                k => return Err(anyhow!("unknown fault {}", k)),
+            }
+        }
+        Ok(f)
+    }
+}
+
+/// `T` with [Faults] injected. Counts what it injected so a run can be checked afterwards.
+pub struct Faulty<T> {
+    pub inner : T,
+    pub faults : Faults,
+    /// xorshift state
+    rng : u64,
+    /// reads left returning `last`
+    stuck_left : u32,
+    last : Option<(Climate, Climate)>,
+    last_raw : Option<f32>,
+    pub injected : u32,
+}
+
+impl<T> Faulty<T> {
+    pub fn new(inner : T, faults : Faults) -> Self {
+        let rng = faults.seed.max(1);
+        Faulty { inner, faults, rng, stuck_left : 0, last : None, last_raw : None, injected : 0 }
+    }
+
+    /// true with probability `p`
+    fn chance(&mut self, p : f32) -> bool {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        let hit = ((self.rng >> 40) as f32 / (1u64 << 24) as f32) < p;
+        if hit { self.injected += 1; }
+        hit
+    }
+
+    fn delay(&mut self) {
+        if self.chance(self.faults.slow) {
+            std::thread::sleep(Duration::from_millis(self.faults.latency_ms as u64));
+        }
+    }
+
+    /// whether this read repeats the last value
+    fn stuck(&mut self) -> bool {
+        if self.stuck_left > 0 {
+            self.stuck_left -= 1;
+            return true;
+        }
+        if self.chance(self.faults.stuck) {
+            self.stuck_left = self.faults.stuck_for.saturating_sub(1);
+            return true;
+        }
+        false
+    }
+}
+
+impl<T : ConvertedRead> ConvertedRead for Faulty<T> {
+    fn read(&mut self) -> anyhow::Result<f32> {
+        self.delay();
+        if self.chance(self.faults.error) {
+            return Err(anyhow!("injected read error"));
+        }
+        if let (true, Some(x)) = (self.stuck(), self.last_raw) {
+            return Ok(x);
+        }
+        let x = self.inner.read()?;
+        self.last_raw = Some(x);
+        Ok(if self.chance(self.faults.nan) { f32::NAN } else { x })
+    }
+    fn set_gain(&mut self, gain : Gain) -> anyhow::Result<()> {
+        self.inner.set_gain(gain)
+    }
+    fn peak(&mut self) -> Option<f32> {
+        self.inner.peak()
+    }
+    fn power(&mut self) -> Option<crate::PowerReading> {
+        self.inner.power()
+    }
+}
+
+impl<T : Scale> Scale for Faulty<T> {}
+impl<T : CurrentSensor> CurrentSensor for Faulty<T> {}
+
+impl<T : ClimatePair> ClimatePair for Faulty<T> {
+    fn read(&mut self) -> anyhow::Result<(Climate, Climate)> {
+        self.delay();
+        if self.chance(self.faults.error) {
+            return Err(anyhow!("injected i2c error"));
+        }
+        if let (true, Some(x)) = (self.stuck(), self.last) {
+            return Ok(x);
+        }
+        let (mut inside, outside) = self.inner.read()?;
+        self.last = Some((inside, outside));
+        if self.chance(self.faults.nan) {
+            inside = Climate { temperature : f32::NAN, humidity : f32::NAN };
+        }
+        Ok((inside, outside))
+    }
+    fn heating(&self) -> [bool; 2] {
+        self.inner.heating()
+    }
+    fn status(&mut self) -> anyhow::Result<[Option<u16>; 2]> {
+        if self.chance(self.faults.error) {
+            return Err(anyhow!("injected i2c error"));
+        }
+        self.inner.status()
+    }
+    fn channels(&self) -> Vec<Channel> {
+        self.inner.channels()
+    }
+}
+
+/// reads pass through, writes are delayed and fail with `write_error`
+impl<T : BlobStore> BlobStore for Faulty<T> {
+    fn get_blob(&self, key : &str) -> anyhow::Result<Option<Vec<u8>>> {
+        self.inner.get_blob(key)
+    }
+    fn set_blob(&mut self, key : &str, blob : &[u8]) -> anyhow::Result<()> {
+        self.delay();
+        if self.chance(self.faults.write_error) {
+            return Err(anyhow!("injected flash write error for {}", key));
+        }
+        self.inner.set_blob(key, blob)
+    }
+    fn keys(&self) -> anyhow::Result<Vec<String>> {
+        self.inner.keys()
+    }
+    fn erase(&mut self, key : &str) -> anyhow::Result<bool> {
+        if self.chance(self.faults.write_error) {
+            return Err(anyhow!("injected flash erase error for {}", key));
+        }
+        self.inner.erase(key)
+    }
+}
@@ -0,0 +1,183 @@
+//! Curves through calibration points: least squares polynomials and piecewise-linear
+//! interpolation. At most a quadratic is fitted to a few dozen points, so the normal
+//! equations are solved directly in f64 instead of pulling in GSL, which doesn't build for
+//! ESP-IDF.
+
+/// coefficients of the polynomial of `degree` closest to `points` (lowest power first, in
+/// f64 because raw counts are in the millions), None without more distinct x than `degree`
+pub fn polyfit(points : &[[f32; 2]], degree : usize) -> Option<Vec<f64>> {
+    let n = degree + 1;
+    let mut xs : Vec<f32> = points.iter().map(|p| p[0]).collect();
+    xs.sort_by(|a, b| a.total_cmp(b));
+    xs.dedup();
+    if xs.len() < n {
+        return None;
+    }
+    // center x, raw HX711 counts are around 1e6 and their squares lose everything in f32
+    let mean = points.iter().map(|p| p[0] as f64).sum::<f64>() / points.len() as f64;
+    // augmented normal equations [X'X | X'y]
+    let mut a = vec![vec![0.0f64; n + 1]; n];
+    for p in points {
+        let x = p[0] as f64 - mean;
+        for (i, row) in a.iter_mut().enumerate() {
+            for (j, v) in row.iter_mut().take(n).enumerate() {
+                *v += x.powi((i + j) as i32);
+            }
+            row[n] += x.powi(i as i32) * p[1] as f64;
+        }
+    }
+    let c = solve(a)?;
+    Some(shift(&c, mean))
+}
+
+/// gaussian elimination with partial pivoting
+fn solve(mut a : Vec<Vec<f64>>) -> Option<Vec<f64>> {
+    let n = a.len();
+    for k in 0..n {
+        let pivot = (k..n).max_by(|&i, &j| a[i][k].abs().total_cmp(&a[j][k].abs()))?;
+        if a[pivot][k].abs() < 1e-12 {
+            return None;
+        }
+        a.swap(k, pivot);
+        for i in k + 1..n {
+            let f = a[i][k] / a[k][k];
+            for j in k..=n {
+                a[i][j] -= f * a[k][j];
+            }
+        }
+    }
+    let mut c = vec![0.0; n];
+    for k in (0..n).rev() {
+        let s : f64 = (k + 1..n).map(|j| a[k][j] * c[j]).sum();
+        c[k] = (a[k][n] - s) / a[k][k];
+    }
+    Some(c)
+}
+
+/// coefficients in x of the polynomial with coefficients `c` in x - `mean`
+fn shift(c : &[f64], mean : f64) -> Vec<f64> {
+    let mut out = vec![0.0; c.len()];
+    for (k, &ck) in c.iter().enumerate() {
+        // ck (x - mean)^k, expanded binomially
+        let mut binom = 1.0;
+        for j in 0..=k {
+            out[j] += ck * binom * (-mean).powi((k - j) as i32);
+            binom = binom * (k - j) as f64 / (j + 1) as f64;
+        }
+    }
+    out
+}
+
+/// the polynomial with coefficients `c` at `x`
+pub fn polyval(c : &[f64], x : f32) -> f32 {
+    c.iter().rev().fold(0.0, |acc, &c| acc * x as f64 + c) as f32
+}
+
+/// straight lines between `points` sorted by x, extended past the ends by the first and
+/// last segment
+pub fn interpolate(points : &[[f32; 2]], x : f32) -> f32 {
+    match points {
+        [] => x,
+        [p] => p[1] + x - p[0],
+        _ => {
+            let k = points.partition_point(|p| p[0] < x).clamp(1, points.len() - 1);
+            let (a, b) = (points[k - 1], points[k]);
+            if b[0] == a[0] {
+                return b[1];
+            }
+            a[1] + (x - a[0]) * (b[1] - a[1]) / (b[0] - a[0])
+        },
+    }
+}
+
+/// 1 - (residual sum of squares) / (total sum of squares), 1 when the y are all the same and
+/// the fit goes through them
+pub fn r_squared(points : &[[f32; 2]], residuals : &[f32]) -> f32 {
+    if points.is_empty() {
+        return f32::NAN;
+    }
+    let mean = points.iter().map(|p| p[1]).sum::<f32>() / points.len() as f32;
+    let total : f32 = points.iter().map(|p| (p[1] - mean).powi(2)).sum();
+    let residual : f32 = residuals.iter().map(|r| r * r).sum();
+    if total == 0.0 {
+        return if residual == 0.0 { 1.0 } else { 0.0 };
+    }
+    1.0 - residual / total
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a : f64, b : f64, tol : f64) -> bool {
+        (a - b).abs() <= tol * b.abs().max(1.0)
+    }
+
+    #[test]
+    fn exact_line() {
+        // an HX711 at 420 counts per g with 8000 counts empty
+        let points = [[8000.0, 0.0], [218_000.0, 500.0], [428_000.0, 1000.0]];
+        let c = polyfit(&points, 1).unwrap();
+        assert!(close(c[0], -8000.0 / 420.0, 1e-9) && close(c[1], 1.0 / 420.0, 1e-9), "{:?}", c);
+        for p in points {
+            assert!((polyval(&c, p[0]) - p[1]).abs() < 1e-3);
+        }
+        let residuals : Vec<f32> = points.iter().map(|p| p[1] - polyval(&c, p[0])).collect();
+        assert!((r_squared(&points, &residuals) - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn exact_parabola() {
+        let f = |x : f32| 2.0 - 3.0 * x + 0.5 * x * x;
+        let points : Vec<[f32; 2]> = [-2.0, 0.0, 1.0, 3.0, 4.0].iter().map(|&x| [x, f(x)]).collect();
+        let c = polyfit(&points, 2).unwrap();
+        assert!(close(c[0], 2.0, 1e-9) && close(c[1], -3.0, 1e-9) && close(c[2], 0.5, 1e-9), "{:?}", c);
+    }
+
+    #[test]
+    fn least_squares_residuals() {
+        // y = x with the middle point 1 too high: the line moves up by 1/3 and the residuals
+        // sum to 0
+        let points = [[0.0, 0.0], [1.0, 2.0], [2.0, 2.0]];
+        let c = polyfit(&points, 1).unwrap();
+        assert!(close(c[0], 1.0 / 3.0, 1e-9) && close(c[1], 1.0, 1e-9), "{:?}", c);
+        let residuals : Vec<f32> = points.iter().map(|p| p[1] - polyval(&c, p[0])).collect();
+        assert!(residuals.iter().sum::<f32>().abs() < 1e-6);
+        let expected = [-1.0 / 3.0, 2.0 / 3.0, -1.0 / 3.0];
+        for (r, e) in residuals.iter().zip(expected) {
+            assert!((r - e).abs() < 1e-6, "{:?}", residuals);
+        }
+        // total sum of squares 8/3, residual 2/3
+        assert!((r_squared(&points, &residuals) - 0.75).abs() < 1e-6);
+    }
+
+    #[test]
+    fn too_few_points() {
+        assert_eq!(polyfit(&[], 1), None);
+        assert_eq!(polyfit(&[[1.0, 2.0]], 1), None);
+        // the same x twice doesn't make a line
+        assert_eq!(polyfit(&[[1.0, 2.0], [1.0, 3.0]], 1), None);
+        assert_eq!(polyfit(&[[0.0, 0.0], [1.0, 1.0]], 2), None);
+        assert_eq!(polyfit(&[[5.0, 1.0]], 0), Some(vec![1.0]));
+    }
+
+    #[test]
+    fn interpolate_between_and_past_the_points() {
+        assert_eq!(interpolate(&[], 7.0), 7.0);
+        assert_eq!(interpolate(&[[1.0, 11.0]], 7.0), 17.0);
+        let points = [[0.0, 0.0], [10.0, 100.0], [20.0, 120.0]];
+        assert_eq!(interpolate(&points, 5.0), 50.0);
+        assert_eq!(interpolate(&points, 10.0), 100.0);
+        assert_eq!(interpolate(&points, 15.0), 110.0);
+        // the end segments extended
+        assert_eq!(interpolate(&points, -1.0), -10.0);
+        assert_eq!(interpolate(&points, 30.0), 140.0);
+    }
+
+    #[test]
+    fn r_squared_edge_cases() {
+        assert!(r_squared(&[], &[]).is_nan());
+        assert_eq!(r_squared(&[[1.0, 5.0], [2.0, 5.0]], &[0.0, 0.0]), 1.0);
+        assert_eq!(r_squared(&[[1.0, 5.0], [2.0, 5.0]], &[1.0, 0.0]), 0.0);
+    }
+}
@@ -0,0 +1,133 @@
+//! What the control logic and the http handlers need from the board. The ESP-IDF
+//! implementations are next to their drivers (sht.rs, linearly_calibrated.rs,
+//! stepper.rs, ir.rs) behind the `esp` feature; host.rs has stand-ins for a laptop.
+
+use std::time::{SystemTime, UNIX_EPOCH, Duration};
+
+/// one SHT31 reading
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Climate {
+    /// °C
+    pub temperature : f32,
+    /// relative humidity 0 to 100
+    pub humidity : f32,
+}
+
+/// one of the sensors behind a [ClimatePair] and its last reading
+#[derive(Clone, Debug)]
+pub struct Channel {
+    pub name : String,
+    pub role : crate::Role,
+    pub climate : Climate,
+    pub heating : bool,
+}
+
+/// the sensors inside and outside the dehydrator, read together
+pub trait ClimatePair {
+    /// (inside, outside)
+    fn read(&mut self) -> anyhow::Result<(Climate, Climate)>;
+    /// whether the last (inside, outside) read was taken with the sensor's heater on, or
+    /// while it was cooling down afterwards, when the humidity reads low
+    fn heating(&self) -> [bool; 2] {
+        [false, false]
+    }
+    /// the status registers, cleared after reading. None for a sensor without one
+    fn status(&mut self) -> anyhow::Result<[Option<u16>; 2]> {
+        Ok([None, None])
+    }
+    /// every sensor of the last read when there are more than the two, see [crate::sht::ShtArray]
+    fn channels(&self) -> Vec<Channel> {
+        Vec::new()
+    }
+}
+
+/// names of the flags set in an SHT31 status register (datasheet table 17)
+pub fn sht_flags(status : u16) -> Vec<String> {
+    [(15, "alert pending"),
+     (13, "heater on"),
+     (11, "humidity alert"),
+     (10, "temperature alert"),
+     (4, "reset detected"),
+     (1, "command failed"),
+     (0, "checksum failed")]
+        .iter()
+        .filter(|(bit, _)| status & (1 << bit) != 0)
+        .map(|(_, name)| name.to_string())
+        .collect()
+}
+
+pub use crate::Gain;
+
+/// allow reading i32 from Hx711 or a u16 from ACS712
+///
+/// the raw reading before [crate::Calibration] is applied
+pub trait ConvertedRead {
+    fn read(&mut self) -> anyhow::Result<f32>;
+    /// for the readings after the next one
+    fn set_gain(&mut self, gain : Gain) -> anyhow::Result<()> {
+        Err(anyhow::anyhow!("no gain to set to {:?}", gain))
+    }
+    /// for a sensor that reads a burst ([crate::rms]), its largest raw value
+    fn peak(&mut self) -> Option<f32> {
+        None
+    }
+    /// For a sensor whose raw signal sits on an offset that it takes off itself ([crate::rms]),
+    /// make the present offset its zero and give how far that moved it, in raw units. None
+    /// for the others, whose calibration is shifted instead
+    fn zero_offset(&mut self) -> Option<anyhow::Result<f32>> {
+        None
+    }
+    /// for a power meter ([crate::pzem]), everything it measured in the last read
+    fn power(&mut self) -> Option<crate::PowerReading> {
+        None
+    }
+}
+
+/// the load cell under the dehydrator
+pub trait Scale : ConvertedRead {}
+
+/// the heater current
+pub trait CurrentSensor : ConvertedRead {}
+
+/// temperature probes in the food, see probe.rs
+pub trait Probes {
+    /// °C of each probe, NaN for one that didn't answer
+    fn read(&mut self) -> anyhow::Result<Vec<f32>>;
+}
+
+/// the stepper turning the thermostat dial, in half steps
+pub trait Dial {
+    /// move to a fraction (0,1) of the range
+    fn set_fraction(&mut self, f : f32) -> anyhow::Result<()>;
+    /// move by `steps`, negative towards min, stopping at the limits
+    fn jog(&mut self, steps : i32) -> anyhow::Result<()>;
+    /// find the lower limit again, which becomes 0 of a range of `length` steps
+    fn recalibrate(&mut self, length : i32) -> anyhow::Result<()>;
+    /// (min, max, pos)
+    fn position(&self) -> (i32, i32, i32);
+}
+
+/// turns the dehydrator off
+pub trait Shutdown {
+    fn shutdown(&mut self) -> anyhow::Result<()>;
+}
+
+/// wall clock and delays, so that a simulation can run faster than real time
+pub trait Clock {
+    /// seconds since the epoch
+    fn now(&self) -> i64;
+    fn delay_ms(&self, ms : u32);
+}
+
+/// std time and sleep, which on the esp32 are libc `time` and a FreeRTOS delay
+#[derive(Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> i64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64)
+    }
+    fn delay_ms(&self, ms : u32) {
+        std::thread::sleep(Duration::from_millis(ms as u64));
+    }
+}
@@ -0,0 +1,71 @@
+//! Stand-ins for the board so the control logic and the http handlers build and run on a laptop.
+
+use log::*;
+
+use crate::hal::{Climate, ClimatePair, ConvertedRead, Scale, CurrentSensor, Dial, Shutdown};
+
+/// always reads the same (inside, outside)
+pub struct FixedClimate(pub Climate, pub Climate);
+
+impl ClimatePair for FixedClimate {
+    fn read(&mut self) -> anyhow::Result<(Climate, Climate)> {
+        Ok((self.0, self.1))
+    }
+}
+
+/// a raw reading that never changes
+pub struct FixedReading(pub f32);
+
+impl ConvertedRead for FixedReading {
+    fn read(&mut self) -> anyhow::Result<f32> {
+        Ok(self.0)
+    }
+}
+
+impl Scale for FixedReading {}
+impl CurrentSensor for FixedReading {}
+
+/// a stepper without a motor: it is wherever it was told to go
+pub struct VirtualDial {
+    pub min : i32,
+    pub max : i32,
+    pub pos : i32,
+}
+
+impl VirtualDial {
+    pub fn new(max : i32) -> Self {
+        VirtualDial { min : 0, max, pos : 0 }
+    }
+}
+
+impl Dial for VirtualDial {
+    fn set_fraction(&mut self, f : f32) -> anyhow::Result<()> {
+        self.pos = self.min + ((self.max - self.min) as f32 * f) as i32;
+        Ok(())
+    }
+    fn jog(&mut self, steps : i32) -> anyhow::Result<()> {
+        self.pos = (self.pos + steps).clamp(self.min, self.max);
+        Ok(())
+    }
+    fn recalibrate(&mut self, length : i32) -> anyhow::Result<()> {
+        *self = VirtualDial::new(length);
+        Ok(())
+    }
+    fn position(&self) -> (i32, i32, i32) {
+        (self.min, self.max, self.pos)
+    }
+}
+
+/// counts shutdowns instead of flashing the ir led
+#[derive(Default)]
+pub struct LogShutdown {
+    pub count : u32,
+}
+
+impl Shutdown for LogShutdown {
+    fn shutdown(&mut self) -> anyhow::Result<()> {
+        self.count += 1;
+        info!("shutdown #{}", self.count);
+        Ok(())
+    }
+}
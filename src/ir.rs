@@ -1,5 +1,6 @@
 use std::sync::{Mutex, Arc};
 
+use crate::hal::Shutdown;
 use esp_idf_hal::{gpio::OutputPin, rmt::{FixedLengthSignal, TxRmtDriver, RmtTransmitConfig, Pulse, PinState, PulseTicks, RmtChannel}, peripheral::Peripheral};
 
 pub struct IrShutdown<'d> (Arc<Mutex<IrShutdownState<'d>>>);
@@ -8,19 +9,19 @@ pub struct IrShutdown<'d> (Arc<Mutex<IrShutdownState<'d>>>);
 impl<'d> FnOnce<()> for IrShutdown<'d> {
     type Output = ();
     extern "rust-call" fn call_once(self, _args: ()) {
-        self.0.lock().unwrap().send_signal();
+        self.0.lock().unwrap().send_signal().unwrap();
     }
 }
 
 impl<'d> Fn<()> for IrShutdown<'d> {
     extern "rust-call" fn call(&self, _args: ()) {
-        self.0.lock().unwrap().send_signal();
+        self.0.lock().unwrap().send_signal().unwrap();
     }
 }
 
 impl<'d> FnMut<()> for IrShutdown<'d> {
     extern "rust-call" fn call_mut(&mut self, _args: ()) {
-        self.0.lock().unwrap().send_signal();
+        self.0.lock().unwrap().send_signal().unwrap();
     }
 }
 
@@ -30,8 +31,14 @@ struct IrShutdownState<'d> {
 }
 
 impl<'d> IrShutdownState<'d> {
-    fn send_signal(&mut self) {
-        self.tx.start_blocking(&self.signal).unwrap();
+    fn send_signal(&mut self) -> anyhow::Result<()> {
+        Ok(self.tx.start_blocking(&self.signal)?)
+    }
+}
+
+impl<'d> Shutdown for IrShutdown<'d> {
+    fn shutdown(&mut self) -> anyhow::Result<()> {
+        self.0.lock().unwrap().send_signal()
     }
 }
 
@@ -2,7 +2,7 @@
 /// which is used by www/app.ts
 use typescript_type_def::TypeDef; // probably should be optional
 
-#[derive(Serialize, Deserialize, TypeDef)]
+#[derive(Serialize, Deserialize, TypeDef, Clone)]
 pub struct Config {
     /// step_times and step_fracs define a piecewise constant function
     /// for the stepper motor position which in turn determines the temperature profile
@@ -23,6 +23,20 @@ pub struct Config {
     /// humidity threshold for dehydrator to be shut down
     w_cut: f32,
 
+    /// °C every food probe must have reached before the dehydrator is shut down for being
+    /// dry, 0 to not wait for them. 71 is the usual one for jerky
+    #[serde(default)]
+    probe_target: f32,
+
+    /// barometric pressure in kPa for the humidity ratio, enthalpy and wet bulb, 0 for sea
+    /// level (101.325)
+    #[serde(default)]
+    pressure_kpa: f32,
+
+    /// zero the scale when a run starts, for loading the trays after posting the config
+    #[serde(default)]
+    zero_scale_at_start: bool,
+
     /// system time when the config was last modified (by http). It is subtracted from
     /// libc::time to get step_times
     last_modified: i64,
@@ -33,11 +47,367 @@ pub struct Config {
 /// so that the current measurement is 3.14. The second sensor's calibration is unchanged.
 /// Only the first sensor's calibration is saved in flash.
 ///
+/// Each `y` adds a point to the calibration, or replaces the point that already had that
+/// `y`, so taring again at 0 moves the zero.
+///
 /// TODO remove hardcoded 2?
 #[derive(Serialize, Deserialize, TypeDef)]
 pub struct CalibrationRequest {
     save : [bool;2],
     y : [Option<f32>;2],
+    /// drop the points before adding `y`
+    #[serde(default)]
+    clear : [bool;2],
+    /// change how the points are fitted
+    #[serde(default)]
+    fit : [Option<Fit>;2],
+}
+
+/// how the points of a [Calibration] become a curve
+#[derive(Serialize, Deserialize, TypeDef, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Fit {
+    /// least squares line
+    #[default]
+    Linear,
+    /// least squares parabola, for at least 3 points
+    Quadratic,
+    /// straight lines between neighbouring points, through every point
+    Piecewise,
+}
+
+/// a sensor's calibration as it is kept in flash: the (raw, calibrated) points and the curve
+/// fitted through them. Without enough points for `fit` it falls back to a line, with one
+/// point to an offset, and without any to the raw value
+#[derive(Serialize, Deserialize, TypeDef, Clone, PartialEq, Debug)]
+pub struct Calibration {
+    /// [x, y], sorted by x
+    points : Vec<[f32;2]>,
+    fit : Fit,
+    /// of the polynomial, lowest power first, empty for piecewise
+    coefficients : Vec<f64>,
+}
+
+/// what saved a [CalibrationRecord]
+#[derive(Serialize, Deserialize, TypeDef, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum CalibrationTrigger {
+    /// `POST /calib` with `save`
+    Request,
+    /// `POST /calib/rollback`
+    Rollback,
+}
+
+/// a calibration as it was saved, kept in the history of the sensor
+#[derive(Serialize, Deserialize, TypeDef, Clone, Debug)]
+pub struct CalibrationRecord {
+    /// seconds since the epoch
+    time : i64,
+    trigger : CalibrationTrigger,
+    calibration : Calibration,
+}
+
+/// element of the response of `GET /api/v1/calib/history`
+#[derive(Serialize, Deserialize, TypeDef, Clone)]
+pub struct CalibrationHistory {
+    name : String,
+    /// oldest first
+    records : Vec<CalibrationRecord>,
+    /// between each record and the next: the largest change of the calibrated value at the
+    /// points of the newer one
+    drift : Vec<f32>,
+}
+
+/// body of `POST /api/v1/calib/rollback`: make an earlier calibration the current one again
+#[derive(Serialize, Deserialize, TypeDef)]
+pub struct RollbackRequest {
+    /// 0 for the current sensor, 1 for the scale, as in [CalibrationRequest]
+    sensor : usize,
+    /// of the [CalibrationRecord]
+    time : i64,
+}
+
+/// what moved the zero of a sensor
+#[derive(Serialize, Deserialize, TypeDef, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ZeroTrigger {
+    /// `POST /zero`, when the trays are empty
+    Request,
+    /// a run started with `zero_scale_at_start`
+    RunStart,
+    /// the current fell by the heater's, the thermostat opened
+    HeaterOff,
+    /// the dehydrator was switched off, nothing draws current
+    Shutdown,
+}
+
+/// one automatic or requested zero of a sensor
+#[derive(Serialize, Deserialize, TypeDef, Clone, Debug)]
+pub struct ZeroShift {
+    /// seconds since the epoch
+    time : i64,
+    trigger : ZeroTrigger,
+    /// what the sensor read before it was zeroed, in calibrated units
+    shift : f32,
+}
+
+/// body of `POST /api/v1/zero`: the sensor reads 0 now, e.g. the trays are empty
+#[derive(Serialize, Deserialize, TypeDef)]
+pub struct ZeroRequest {
+    /// 0 for the current sensor, 1 for the scale, as in [CalibrationRequest]
+    sensor : usize,
+}
+
+/// element of the response of `GET /api/v1/calib`
+#[derive(Serialize, Deserialize, TypeDef, Clone)]
+pub struct CalibrationReport {
+    name : String,
+    calibration : Calibration,
+    /// y minus the calibrated value of each point
+    residuals : Vec<f32>,
+    r_squared : f32,
+    /// since boot, oldest first. They move the calibration in memory, which a `save` keeps
+    #[serde(default)]
+    zero_shifts : Vec<ZeroShift>,
+}
+
+/// body of `POST /login`. The response sets a `session` cookie that the
+/// state-changing endpoints accept in place of `Authorization: Bearer <password>`
+#[derive(Serialize, Deserialize, TypeDef)]
+pub struct LoginRequest {
+    password : String,
+}
+
+/// body of `POST /password`, with the current password or a session. The first password
+/// is built into the firmware, see auth.rs
+#[derive(Serialize, Deserialize, TypeDef)]
+pub struct PasswordRequest {
+    password : String,
+    /// GET endpoints (config, calibration, measurements) stay usable without logging in
+    open_reads : bool,
+}
+
+/// body of `POST /api/v1/stepper/jog`: half steps to move, negative towards the low end of the dial
+#[derive(Serialize, Deserialize, TypeDef)]
+pub struct JogRequest {
+    steps : i32,
+}
+
+/// body of `POST /api/v1/stepper/fraction`: go to this fraction (0,1) of the full range
+#[derive(Serialize, Deserialize, TypeDef)]
+pub struct FractionRequest {
+    fraction : f32,
+}
+
+/// body of `POST /api/v1/stepper/calibrate`: run against the lower limit again
+#[derive(Serialize, Deserialize, TypeDef)]
+pub struct StepperCalibrateRequest {
+    /// half steps between the limits, the saved length when null
+    length : Option<i32>,
+    /// store `length` in flash for the next boot
+    save : bool,
+}
+
+/// where the dial stepper is, in half steps
+#[derive(Serialize, Deserialize, TypeDef)]
+pub struct StepperStatus {
+    min : i32,
+    max : i32,
+    pos : i32,
+    /// the profile thread leaves the stepper alone after a manual move
+    /// until `POST /api/v1/stepper/resume`
+    manual : bool,
+}
+
+/// response to the manual stepper endpoints
+#[derive(Serialize, Deserialize, TypeDef)]
+pub struct StepperMove {
+    before : StepperStatus,
+    after : StepperStatus,
+}
+
+/// one rejected field of a request, for example `{"field":"step_fracs[3]","message":"1.2 is outside [0,1]"}`
+#[derive(Serialize, Deserialize, TypeDef)]
+pub struct FieldError {
+    field : String,
+    message : String,
+}
+
+/// body of a 400 response
+#[derive(Serialize, Deserialize, TypeDef)]
+pub struct ValidationErrors {
+    errors : Vec<FieldError>,
+}
+
+/// where a climate sensor is, see [crate::sht::Placement]. Serialized as `{"tray":1}`, `"exhaust"`
+#[derive(Serialize, Deserialize, TypeDef, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// numbered from the bottom
+    Tray(u8),
+    Exhaust,
+    Ambient,
+}
+
+/// one climate sensor in [Status]
+#[derive(Serialize, Deserialize, TypeDef, Clone)]
+pub struct SensorStatus {
+    name : String,
+    role : Role,
+    temperature : f32,
+    humidity : f32,
+    heater : bool,
+}
+
+/// how much the trays differ in one sample, over the tray sensors that answered
+#[derive(Serialize, Deserialize, TypeDef, Clone, Default, Debug)]
+pub struct Uniformity {
+    trays : u8,
+    /// max - min, °C
+    temp_range : f32,
+    /// population standard deviation, °C
+    temp_sd : f32,
+    /// max - min, %RH
+    rh_range : f32,
+    /// max - min, g/m3, which is what the drying rate follows
+    abs_humidity_range : f32,
+}
+
+/// one read of a power meter ([crate::pzem])
+#[derive(Serialize, Deserialize, TypeDef, Clone, Debug, PartialEq)]
+pub struct PowerReading {
+    volts : f32,
+    amps : f32,
+    watts : f32,
+    /// since the meter's counter was last reset
+    energy_wh : f32,
+    hz : f32,
+    power_factor : f32,
+}
+
+/// which sensor measures the heater
+#[derive(Serialize, Deserialize, TypeDef, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CurrentSource {
+    /// hall sensor inside the dehydrator on an adc pin
+    #[default]
+    Acs712,
+    /// PZEM-004T on the uart, which also measures voltage, power and energy
+    Pzem,
+    /// clamp on the mains lead, on the ACS712's adc pin with a bias network
+    Sct013 {
+        /// 2000 for the SCT-013-000
+        turns : f32,
+        burden_ohms : f32,
+    },
+}
+
+/// input attenuation of an adc channel, which sets the range the esp32c3 reads
+#[derive(Serialize, Deserialize, TypeDef, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Attenuation {
+    /// up to 750 mV
+    Db0,
+    /// up to 1050 mV
+    Db2_5,
+    /// up to 1300 mV
+    Db6,
+    /// up to 2500 mV
+    Db11,
+}
+
+/// input and gain of an HX711. A calibration only holds for the gain it was made at
+#[derive(Serialize, Deserialize, TypeDef, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Gain {
+    /// channel A at 128, what the HX711 starts with
+    A128,
+    A64,
+    /// channel B, always 32
+    B32,
+}
+
+/// body of `GET/POST /api/v1/hardware`: which sensors are fitted. Kept in flash and
+/// applied at the next boot
+#[derive(Serialize, Deserialize, TypeDef, Clone, Default, Debug)]
+pub struct Hardware {
+    #[serde(default)]
+    current : CurrentSource,
+    /// of the adc pin of the ACS712 or SCT-013, null for 0 dB and 11 dB respectively
+    #[serde(default)]
+    current_attenuation : Option<Attenuation>,
+    /// SHT31s on the trays besides the inside and outside ones, for the uniformity in
+    /// `/status`. None by default
+    #[serde(default)]
+    trays : Vec<TraySensor>,
+    /// of the HX711, null for a128. The scale needs calibrating again after a change
+    #[serde(default)]
+    scale_gain : Option<Gain>,
+}
+
+/// an SHT31 on a tray, called "tray n" in `/status` and `/sensors.csv`
+#[derive(Serialize, Deserialize, TypeDef, Clone, Copy, PartialEq, Debug)]
+pub struct TraySensor {
+    /// numbered from the bottom
+    tray : u8,
+    /// 0x44 with ADDR low, 0x45 with ADDR high
+    address : u8,
+    /// [address of a TCA9548A (0x70 to 0x77), channel 0 to 7] between the bus and the
+    /// sensor, null when it is on the bus itself
+    #[serde(default)]
+    mux : Option<[u8; 2]>,
+}
+
+/// what else follows from a temperature and humidity, see src/psychro.rs
+#[derive(Serialize, Deserialize, TypeDef, Clone, Copy, Default, Debug)]
+pub struct Psychrometrics {
+    /// °C
+    dew_point : f32,
+    /// vapor pressure deficit
+    vpd_kpa : f32,
+    /// g of water per kg of dry air
+    humidity_ratio_g_per_kg : f32,
+    /// kJ per kg of dry air
+    enthalpy_kj_per_kg : f32,
+    /// °C
+    wet_bulb : f32,
+}
+
+/// response of `GET /api/v1/status`: the latest sample and the state of the sensors.
+/// NaN (a sensor that didn't answer) is null
+#[derive(Serialize, Deserialize, TypeDef, Clone, Default)]
+pub struct Status {
+    /// seconds since the epoch of the sample, 0 before the first one
+    time : i64,
+    inside_temp : f32,
+    inside_rh : f32,
+    outside_temp : f32,
+    outside_rh : f32,
+    amps : f32,
+    grams : f32,
+    /// (inside, outside) sht31 heater on or cooling down, so the humidity reads low
+    heater : [bool; 2],
+    /// flags in the (inside, outside) sht31 status registers at the end of the last blob
+    alerts : [Vec<String>; 2],
+    /// samples of the last blob without temperature or humidity
+    climate_errors : i32,
+    /// samples of the last blob without current or weight
+    sensor_errors : i32,
+    /// amps at the top of the sine, null without burst sampling
+    amps_peak : f32,
+    /// with a power meter
+    power : Option<PowerReading>,
+    /// the weight stayed put for a few measurements
+    stable : bool,
+    /// °C of each food probe
+    probes : Vec<f32>,
+    /// every climate sensor, including the two above
+    sensors : Vec<SensorStatus>,
+    /// null with fewer than two tray sensors
+    uniformity : Option<Uniformity>,
+    /// of (inside, outside) at the configured pressure
+    psychrometrics : [Psychrometrics; 2],
 }
 
-pub type API = (Config, CalibrationRequest);
+pub type API = (Config, CalibrationRequest, CalibrationReport, CalibrationHistory, RollbackRequest, ZeroRequest, LoginRequest, PasswordRequest, ValidationErrors,
+                JogRequest, FractionRequest, StepperCalibrateRequest, StepperMove, Status, Hardware);
@@ -0,0 +1,113 @@
+#![feature(trait_alias)]
+#![feature(fn_traits)]
+#![feature(unboxed_closures)]
+
+//! Everything but `main`. With `--no-default-features --features host` this builds
+//! without ESP-IDF, which leaves out the drivers (the `esp` feature).
+
+use serde::{Serialize, Deserialize};
+
+include!("json.rs");
+
+/// traits between the control logic and the board
+pub mod hal;
+
+/// the profile thread and the measurement loop
+pub mod control;
+
+/// handlers for the http api
+pub mod api;
+
+/// stepper motor driver
+pub mod stepper;
+
+/// compress/decompress measurements
+pub mod meas;
+
+/// paths, methods and access of the http api
+pub mod routes;
+
+/// registers typed handlers for [routes::ROUTES]
+pub mod router;
+
+/// checks on a posted [Config] before it replaces the current one
+pub mod validate;
+
+/// cbor blobs in nvs or in memory
+pub mod store;
+
+/// find and manipulate keys for flash storage
+pub mod nvs;
+
+/// sensors and stores that fail on purpose
+pub mod fault;
+
+/// food probe temperatures: DS18B20s on 1-Wire
+pub mod probe;
+
+/// password check and rate limiting for the http handlers
+pub mod auth;
+
+/// wrapper for hx711 and acs712 to make and apply calibrations
+pub mod linearly_calibrated;
+
+/// least squares and piecewise-linear curves through calibration points
+pub mod fit;
+
+/// adc channels in millivolts from the factory calibration
+pub mod adc;
+
+/// rms and peak of the heater current from bursts of samples
+pub mod rms;
+
+/// PZEM-004T power meter over modbus
+pub mod pzem;
+
+/// SCT-013 current transformer on an adc pin
+pub mod sct013;
+
+/// dew point, vpd, humidity ratio, enthalpy and wet bulb of moist air
+pub mod psychro;
+
+/// oversampled, filtered scale readings
+pub mod weigh;
+
+/// the gzipped web interface
+pub mod assets;
+
+/// stand-ins for the sensors and actuators
+#[cfg(feature = "host")]
+pub mod host;
+
+/// a simulated dehydrator behind the same traits
+#[cfg(feature = "host")]
+pub mod sim;
+
+/// recorded measurements played back as sensors
+#[cfg(feature = "host")]
+pub mod replay;
+
+/// connects to wifi
+#[cfg(feature = "esp")]
+pub mod wifi;
+
+/// essid and psk for wifi
+#[cfg(feature = "esp")]
+mod secrets;
+
+/// arrays of SHT31s with retries, resets, heaters and NaN for missing samples
+#[cfg(feature = "esp")]
+pub mod sht;
+
+/// flashes an infrared led to signal shutdown
+#[cfg(feature = "esp")]
+pub mod ir;
+
+
+/// ACS712 current sensor
+#[cfg(feature = "esp")]
+pub mod acs712;
+
+/// runs the [router::Router] on the ESP-IDF http server
+#[cfg(feature = "esp")]
+pub mod esp_http;
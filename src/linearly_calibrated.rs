@@ -1,114 +1,281 @@
 
 use std::sync::{Arc, Mutex};
 
-use esp_idf_hal::{gpio::ADCPin, adc::Adc, spi::{SpiDeviceDriver, SpiDriver}};
-use esp_idf_svc::nvs::{EspNvs, NvsDefault};
-use hx711_spi::Hx711;
+use anyhow::anyhow;
+use log::{info, warn};
 use serde::{Serialize, Deserialize};
 
-use crate::{acs712::ACS172, nvs::{self, EmptyBlob}};
-use esp_idf_sys::EspError;
-use anyhow::anyhow;
+use crate::{Calibration, CalibrationHistory, CalibrationRecord, CalibrationReport, CalibrationTrigger, Fit, ZeroShift, ZeroTrigger,
+            fit, weigh, hal::ConvertedRead, store::{self, BlobStore}};
 
-/// allow reading i32 from Hx711 or a u16 from ACS712
-///
-/// maybe it belongs in acs172.rs or a file imported by it, so that acs172.rs can be more private
-pub trait ConvertedRead {
-    fn read(&mut self) -> anyhow::Result<f32>;
-}
+#[cfg(feature = "esp")]
+mod esp {
+    use esp_idf_hal::spi::{SpiDeviceDriver, SpiDriver};
+    use hx711_spi::{Hx711, Mode};
+    use anyhow::anyhow;
 
-impl<'d> ConvertedRead for Hx711<SpiDeviceDriver<'d, SpiDriver<'d>>> {
-    fn read(&mut self) -> anyhow::Result<f32> {
-        self.read()
-            .map_err(move |e| anyhow!("hx711 error: {:?}", e))
-            .map(|x| x as f32)
-    }
-}
+    use crate::hal::{ConvertedRead, Gain, Scale};
+
+    impl<'d> ConvertedRead for Hx711<SpiDeviceDriver<'d, SpiDriver<'d>>> {
+        fn read(&mut self) -> anyhow::Result<f32> {
+            self.read()
+                .map_err(move |e| anyhow!("hx711 error: {:?}", e))
+                .map(|x| x as f32)
+        }
 
-impl<'d, PIN: ADCPin, ADC: Adc> ConvertedRead for ACS172<'d, PIN, ADC> {
-    fn read(&mut self) -> anyhow::Result<f32> {
-        self.driver.read(&mut self.channel)
-            .map_err(|e : EspError| anyhow!("adc error: {:?}", e))
-            .map(|x| x as f32)
+        fn set_gain(&mut self, gain : Gain) -> anyhow::Result<()> {
+            let mode = match gain {
+                Gain::A128 => Mode::ChAGain128,
+                Gain::A64 => Mode::ChAGain64,
+                Gain::B32 => Mode::ChBGain32,
+            };
+            self.set_mode(mode).map_err(|e| anyhow!("hx711 error: {:?}", e))?;
+            Ok(())
+        }
     }
+
+    impl<'d> Scale for Hx711<SpiDeviceDriver<'d, SpiDriver<'d>>> {}
 }
 
 pub struct CalibratedSensor<'d> {
     pub driver : Arc<Mutex<Box< dyn ConvertedRead + Send + 'd>>>,
     /// calibration stored in memory
-    pub calibration : LinearCalibration,
+    pub calibration : Calibration,
     /// access calibration stored in flash
-    pub nvs : Arc<Mutex<EspNvs<NvsDefault>>>,
+    pub nvs : Arc<Mutex<dyn BlobStore + Send>>,
     pub name : String,
+    /// zero on its own when the heater switches off and after a shutdown
+    pub auto_zero : Option<AutoZero>,
+    /// since boot, the last [MAX_ZERO_SHIFTS]
+    pub zero_shifts : Vec<ZeroShift>,
+    /// largest raw value the driver gives, see [CalibratedSensor::with_raw_max]
+    pub raw_max : Option<f32>,
 }
 
+/// Zero a current sensor at the events that leave a known current: the thermostat opening,
+/// seen as the calibrated reading falling by at least `heater_amps` from one read to the
+/// next, and the IR shutdown ([CalibratedSensor::shut_down]). The fan keeps running in the
+/// off-phase, so the current isn't 0 then, only the raw offset is zeroed
+/// ([ConvertedRead::zero_offset]). The zero is taken at the read after the event, once the
+/// driver measured after it.
+#[derive(Clone, Copy, Debug)]
+pub struct AutoZero {
+    pub heater_amps : f32,
+    last : Option<f32>,
+    due : Option<ZeroTrigger>,
+}
+
+impl AutoZero {
+    pub fn new(heater_amps : f32) -> Self {
+        AutoZero { heater_amps, last : None, due : None }
+    }
+}
+
+/// raw reads averaged for a zero
+const ZERO_SAMPLES : usize = 8;
+pub const MAX_ZERO_SHIFTS : usize = 20;
+
 impl <'d>CalibratedSensor<'d>  {
-    pub fn new(driver : impl ConvertedRead + Send + 'd, nvs : Arc<Mutex<EspNvs<NvsDefault>>>, name : String) -> Self {
+    pub fn new(driver : impl ConvertedRead + Send + 'd, nvs : Arc<Mutex<dyn BlobStore + Send>>, name : String) -> Self {
         let b = Box::new(driver) as Box<dyn ConvertedRead + Send>;
         Self {
             driver : Arc::new(Mutex::new(b)),
-            calibration : LinearCalibration::new(),
+            calibration : Calibration::new(),
             nvs,
             name,
+            auto_zero : None,
+            zero_shifts : Vec::new(),
+            raw_max : None,
         }
     }
 
+    pub fn with_auto_zero(self, auto_zero : AutoZero) -> Self {
+        Self { auto_zero : Some(auto_zero), ..self }
+    }
+
+    /// A saved calibration with a point beyond `raw_max` was made for raw values in another
+    /// unit, like the adc counts the ACS712 read before it read volts, and isn't used.
+    pub fn with_raw_max(self, raw_max : f32) -> Self {
+        Self { raw_max : Some(raw_max), ..self }
+    }
+
+    /// whether `calibration` was made for the raw values of this driver
+    fn fits_driver(&self, calibration : &Calibration) -> bool {
+        self.raw_max.map_or(true, |m| calibration.points.iter().all(|p| p[0].abs() <= m))
+    }
+
     pub fn read(&mut self) -> anyhow::Result<f32> {
         let x = self.driver.lock().unwrap().read()?;
         Ok(self.calibration.predict(x))
     }
 
-    /// overwrite one point of the calibration stored in memory
+    /// what else the driver measured in the last read, uncalibrated
+    pub fn power(&mut self) -> Option<crate::PowerReading> {
+        self.driver.lock().unwrap().power()
+    }
+
+    /// the calibrated peak of the last read, for drivers that have one
+    pub fn peak(&mut self) -> Option<f32> {
+        let x = self.driver.lock().unwrap().peak()?;
+        Some(self.calibration.predict(x))
+    }
+
+    /// add a point to the calibration stored in memory
     /// y is the desired output value for the raw x value from the
     /// raw_read call
     ///
     /// strictly speaking it's only "tare" if y is 0.0
     pub fn tare_measurement(&mut self, y: f32) -> anyhow::Result<()> {
         let x = self.driver.lock().unwrap().read()?;
+        self.calibration.add_point(x, y)
+    }
 
-        if y == 0.0 {
-            self.calibration.x0 = x;
-            self.calibration.y0 = y;
-        } else {
-            self.calibration.x1 = x;
-            self.calibration.y1 = y;
+    pub fn report(&self) -> CalibrationReport {
+        CalibrationReport { zero_shifts : self.zero_shifts.clone(), ..self.calibration.report(&self.name) }
+    }
+
+    /// Make the present raw offset the zero of a driver that takes it off itself, else make
+    /// the present raw reading read 0, from a trimmed mean of a few of them
+    pub fn zero(&mut self, trigger : ZeroTrigger, time : i64) -> anyhow::Result<ZeroShift> {
+        let offset = self.driver.lock().unwrap().zero_offset();
+        let shift = match offset {
+            Some(moved) => {
+                let moved = moved?;
+                self.calibration.predict(moved) - self.calibration.predict(0.0)
+            },
+            None => {
+                let mut xs : Vec<f32> = {
+                    let mut driver = self.driver.lock().unwrap();
+                    (0..ZERO_SAMPLES).filter_map(|_| driver.read().ok()).filter(|x| x.is_finite()).collect()
+                };
+                if xs.is_empty() {
+                    return Err(anyhow!("{}: no reading to zero at", self.name));
+                }
+                self.calibration.zero_at(weigh::trimmed_mean(&mut xs, 0.25))?
+            },
+        };
+        info!("{}: zero moved by {} ({:?})", self.name, shift, trigger);
+        let z = ZeroShift { time, trigger, shift };
+        self.zero_shifts.push(z.clone());
+        let excess = self.zero_shifts.len().saturating_sub(MAX_ZERO_SHIFTS);
+        self.zero_shifts.drain(..excess);
+        Ok(z)
+    }
+
+    /// follow `y`, the latest calibrated reading, for [AutoZero] and zero for an event seen
+    /// at the read before
+    pub fn follow_zero(&mut self, y : f32, time : i64) -> anyhow::Result<()> {
+        let due = match self.auto_zero.as_mut() {
+            Some(a) => {
+                let due = a.due.take();
+                if y.is_finite() {
+                    if a.last.map_or(false, |last| last - y >= a.heater_amps) {
+                        a.due = Some(ZeroTrigger::HeaterOff);
+                    }
+                    a.last = Some(y);
+                }
+                due
+            },
+            None => None,
+        };
+        if let Some(trigger) = due {
+            self.zero(trigger, time)?;
         }
         Ok(())
     }
 
-    pub fn save_calibration(&mut self) -> anyhow::Result<()> {
+    /// the dehydrator was switched off: with [AutoZero], zero at the next read
+    pub fn shut_down(&mut self) {
+        if let Some(a) = self.auto_zero.as_mut() {
+            a.due = Some(ZeroTrigger::Shutdown);
+            a.last = None;
+        }
+    }
+
+    /// save the calibration in memory as the current one and add it to the history, unless
+    /// it is already the one in flash
+    pub fn save_calibration(&mut self, time : i64, trigger : CalibrationTrigger) -> anyhow::Result<()> {
         let mut nvs = self.nvs.lock().unwrap();
-        let writer = nvs::ReadWriteStr(&mut nvs, self.name.as_str());
 
-        if let Ok(saved) = ciborium::de::from_reader(&writer) {
+        if let Ok(Some(saved)) = load_stored(&*nvs, &self.name) {
             if self.calibration == saved {
                 // skip saving duplicated
                 return Ok(());
             };
         };
 
-        ciborium::ser::into_writer(&self.calibration, writer)?;
-        Ok(())
+        store::save(&mut *nvs, &self.name, &self.calibration)?;
+
+        let key = history_key(&self.name);
+        let mut records : Vec<CalibrationRecord> = store::load(&*nvs, &key).ok().flatten().unwrap_or_default();
+        records.push(CalibrationRecord { time, trigger, calibration : self.calibration.clone() });
+        let excess = records.len().saturating_sub(MAX_HISTORY);
+        records.drain(..excess);
+        store::save(&mut *nvs, &key, &records)
+    }
+
+    /// the saved calibrations, oldest first, and how far each moved from the one before
+    pub fn history(&self) -> anyhow::Result<CalibrationHistory> {
+        let records : Vec<CalibrationRecord> = store::load(&*self.nvs.lock().unwrap(), &history_key(&self.name))?
+            .unwrap_or_default();
+        let drift = records.windows(2).map(|w| w[0].calibration.drift(&w[1].calibration)).collect();
+        Ok(CalibrationHistory { name : self.name.clone(), records, drift })
+    }
+
+    /// make the record saved at `time` the current calibration, which adds it to the
+    /// history again
+    pub fn rollback(&mut self, time : i64, now : i64) -> anyhow::Result<CalibrationReport> {
+        let record = self.history()?.records.into_iter().rev()
+            .find(|r| r.time == time)
+            .ok_or_else(|| anyhow!("{}: no calibration saved at {}", self.name, time))?;
+        if !self.fits_driver(&record.calibration) {
+            return Err(anyhow!("{}: the calibration saved at {} has raw values beyond {:?}", self.name, time, self.raw_max));
+        }
+        self.calibration = record.calibration;
+        self.save_calibration(now, CalibrationTrigger::Rollback)?;
+        Ok(self.report())
     }
 
     /// load calibration from flash Ok(false) if it was not found
-    /// and instead the default calibration was loaded
+    /// and instead the default calibration was loaded. One that doesn't fit the driver
+    /// ([CalibratedSensor::with_raw_max]) is erased
     pub fn load_calibration(&mut self) -> anyhow::Result<bool> {
-        let mut nvs = self.nvs.lock().unwrap();
-        let reader = nvs::ReadWriteStr(&mut nvs, self.name.as_str());
+        let store = self.nvs.clone();
+        let mut nvs = store.lock().unwrap();
         // when the calibration is not found, we use the default calibration
-        match ciborium::de::from_reader(reader) {
-            Ok(calibration) => { 
-                self.calibration = calibration; 
-                return Ok(true);},
-            Err(ciborium::de::Error::Io(e)) if e.is::<EmptyBlob>() => 
-                { self.calibration = LinearCalibration::new();
-                  return Ok(false);},
-            Err(e) => { return Err(anyhow!("other error {}", e)); },
+        match load_stored(&*nvs, &self.name)? {
+            Some(calibration) if self.fits_driver(&calibration) => {
+                self.calibration = calibration;
+                Ok(true)
+            },
+            Some(calibration) => {
+                warn!("{}: dropped the saved calibration {:?}, its raw values are beyond {:?}, calibrate again",
+                      self.name, calibration, self.raw_max);
+                nvs.erase(&self.name)?;
+                self.calibration = Calibration::new();
+                Ok(false)
+            },
+            None => {
+                self.calibration = Calibration::new();
+                Ok(false)
+            },
+        }
+    }
+}
+
+/// [CalibratedSensor::load_calibration] for each of `sensors`, at boot. One that can't be
+/// loaded reads raw values until it is calibrated again
+pub fn load_calibrations(sensors : &mut [CalibratedSensor]) {
+    for s in sensors {
+        match s.load_calibration() {
+            Ok(true) => info!("{}: saved calibration {:?}", s.name, s.calibration),
+            Ok(false) => info!("{}: not calibrated", s.name),
+            Err(e) => warn!("{}: saved calibration: {:?}", s.name, e),
         }
     }
 }
 
+/// the two point calibration that was saved before [Calibration]
 #[derive(Serialize, Deserialize, PartialEq, Clone, Copy)]
 pub struct LinearCalibration {
     x0 : f32,
@@ -116,10 +283,216 @@ pub struct LinearCalibration {
     y0 : f32,
     y1 : f32,
 }
-impl LinearCalibration {
-    fn new() -> Self { Self { x0 : 0., x1 : 1., y0 : 0.0, y1 : 1.0 } }
 
-    fn predict(&self, x : f32) -> f32 {
-        self.y0 + (x - self.x0) * (self.y1 - self.y0) / (self.x1 - self.x0)
+impl From<LinearCalibration> for Calibration {
+    fn from(l : LinearCalibration) -> Self {
+        let mut c = Calibration::new();
+        c.points = vec![[l.x0, l.y0], [l.x1, l.y1]];
+        c.points.sort_by(|a, b| a[0].total_cmp(&b[0]));
+        c.refit();
+        c
+    }
+}
+
+/// either format found under a sensor's name in the "calib" namespace
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Stored {
+    Points(Calibration),
+    Linear(LinearCalibration),
+}
+
+fn load_stored(nvs : &(dyn BlobStore + Send), name : &str) -> anyhow::Result<Option<Calibration>> {
+    Ok(store::load::<_, Stored>(nvs, name)?.map(|s| match s {
+        Stored::Points(c) => c,
+        Stored::Linear(l) => l.into(),
+    }))
+}
+
+/// records in the history of a sensor, the oldest is dropped
+pub const MAX_HISTORY : usize = 10;
+
+/// nvs keys are at most 15 characters, sensor names are short
+fn history_key(name : &str) -> String {
+    format!("{}_hist", name)
+}
+
+/// points a calibration keeps, the least squares fits are cheap but live in flash
+pub const MAX_POINTS : usize = 16;
+
+impl Calibration {
+    /// the raw value unchanged
+    pub fn new() -> Self {
+        Calibration { points : Vec::new(), fit : Fit::Linear, coefficients : vec![0.0, 1.0] }
+    }
+
+    pub fn points(&self) -> &[[f32;2]] {
+        &self.points
+    }
+
+    /// add (x, y), replacing the point that had the same y
+    pub fn add_point(&mut self, x : f32, y : f32) -> anyhow::Result<()> {
+        if !x.is_finite() || !y.is_finite() {
+            return Err(anyhow!("calibration point ({}, {}) is not finite", x, y));
+        }
+        self.points.retain(|p| p[1] != y);
+        if self.points.len() >= MAX_POINTS {
+            return Err(anyhow!("a calibration has at most {} points, clear it first", MAX_POINTS));
+        }
+        let k = self.points.partition_point(|p| p[0] < x);
+        self.points.insert(k, [x, y]);
+        self.refit();
+        Ok(())
+    }
+
+    pub fn clear(&mut self) {
+        self.points.clear();
+        self.refit();
+    }
+
+    pub fn set_fit(&mut self, fit : Fit) {
+        self.fit = fit;
+        self.refit();
+    }
+
+    fn refit(&mut self) {
+        let degree = match self.fit {
+            Fit::Linear => 1,
+            Fit::Quadratic => 2,
+            Fit::Piecewise => {
+                self.coefficients.clear();
+                return;
+            },
+        };
+        self.coefficients = (1..=degree).rev()
+            .find_map(|d| fit::polyfit(&self.points, d))
+            .unwrap_or_else(|| match self.points.first() {
+                Some(p) => vec![(p[1] - p[0]) as f64, 1.0],
+                None => vec![0.0, 1.0],
+            });
+    }
+
+    pub fn predict(&self, x : f32) -> f32 {
+        match self.fit {
+            Fit::Piecewise => fit::interpolate(&self.points, x),
+            _ => fit::polyval(&self.coefficients, x),
+        }
+    }
+
+    /// y minus the prediction of each point
+    pub fn residuals(&self) -> Vec<f32> {
+        self.points.iter().map(|p| p[1] - self.predict(p[0])).collect()
+    }
+
+    /// make `x` read (about, for a least squares fit) 0 by moving every point along x by the
+    /// same amount, which keeps the shape of the curve. Without a point at 0 one is added.
+    /// Returns what `x` read before
+    pub fn zero_at(&mut self, x : f32) -> anyhow::Result<f32> {
+        let before = self.predict(x);
+        match self.points.iter().find(|p| p[1] == 0.0).map(|p| p[0]) {
+            Some(x0) => {
+                for p in self.points.iter_mut() {
+                    p[0] += x - x0;
+                }
+                self.refit();
+            },
+            None => self.add_point(x, 0.0)?,
+        }
+        Ok(before)
+    }
+
+    /// largest difference between this and `newer` at the points of `newer`, or at these
+    /// points when `newer` has none
+    pub fn drift(&self, newer : &Calibration) -> f32 {
+        let points = if newer.points.is_empty() { &self.points } else { &newer.points };
+        points.iter().map(|p| (newer.predict(p[0]) - self.predict(p[0])).abs()).fold(0.0, f32::max)
+    }
+
+    pub fn report(&self, name : &str) -> CalibrationReport {
+        let residuals = self.residuals();
+        CalibrationReport {
+            name : name.to_string(),
+            r_squared : fit::r_squared(&self.points, &residuals),
+            residuals,
+            calibration : self.clone(),
+            zero_shifts : Vec::new(),
+        }
+    }
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::store::MemoryStore;
+
+    /// a driver that takes its offset off itself, which moved by 0.01 at every zero
+    struct Offset {
+        zeroed : Arc<Mutex<usize>>,
+    }
+
+    impl ConvertedRead for Offset {
+        fn read(&mut self) -> anyhow::Result<f32> {
+            Ok(0.5)
+        }
+        fn zero_offset(&mut self) -> Option<anyhow::Result<f32>> {
+            *self.zeroed.lock().unwrap() += 1;
+            Some(Ok(0.01))
+        }
+    }
+
+    #[test]
+    fn zeroes_the_offset_when_the_heater_switches_off_and_after_a_shutdown() {
+        let zeroed = Arc::new(Mutex::new(0));
+        let nvs : Arc<Mutex<dyn BlobStore + Send>> = Arc::new(Mutex::new(MemoryStore::default()));
+        let mut s = CalibratedSensor::new(Offset { zeroed : zeroed.clone() }, nvs, "ACS712".to_string())
+            .with_auto_zero(AutoZero::new(1.5));
+        let triggers = |s : &CalibratedSensor| s.zero_shifts.iter().map(|z| z.trigger).collect::<Vec<_>>();
+
+        // the fan alone, then the heater on and wavering, then the thermostat opening: the
+        // zero follows at the read after
+        for y in [0.4, 0.5, 6.0, 6.1, 5.2, 6.0] {
+            s.follow_zero(y, 0).unwrap();
+        }
+        assert!(s.zero_shifts.is_empty());
+        s.follow_zero(0.5, 1).unwrap();
+        assert!(s.zero_shifts.is_empty());
+        s.follow_zero(0.5, 2).unwrap();
+        assert_eq!(triggers(&s), [ZeroTrigger::HeaterOff]);
+        assert_eq!(s.zero_shifts[0].time, 2);
+        assert!((s.zero_shifts[0].shift - 0.01).abs() < 1e-6);
+        s.follow_zero(0.5, 3).unwrap();
+        assert_eq!(s.zero_shifts.len(), 1);
+
+        s.shut_down();
+        s.follow_zero(0.0, 4).unwrap();
+        assert_eq!(triggers(&s), [ZeroTrigger::HeaterOff, ZeroTrigger::Shutdown]);
+        s.follow_zero(f32::NAN, 5).unwrap();
+        s.follow_zero(0.0, 6).unwrap();
+        assert_eq!(s.zero_shifts.len(), 2);
+
+        // the rms reading itself was never shifted
+        assert_eq!(*zeroed.lock().unwrap(), 2);
+        assert!(s.calibration.points().is_empty());
+    }
+
+    #[test]
+    fn no_auto_zero_without_one() {
+        let zeroed = Arc::new(Mutex::new(0));
+        let nvs : Arc<Mutex<dyn BlobStore + Send>> = Arc::new(Mutex::new(MemoryStore::default()));
+        let mut s = CalibratedSensor::new(Offset { zeroed : zeroed.clone() }, nvs, "PZEM".to_string());
+        s.shut_down();
+        for y in [6.0, 0.5, 0.5, 0.0] {
+            s.follow_zero(y, 0).unwrap();
+        }
+        assert!(s.zero_shifts.is_empty());
+        assert_eq!(*zeroed.lock().unwrap(), 0);
     }
 }
@@ -1,20 +1,90 @@
 use serde::{Deserialize, Serialize};
 
+use crate::psychro;
+
 /// number of measurements saved in ram to be compressed
 /// and saved in a single blob
 pub const N1 : usize = 100;
 
-#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+/// food probes with a column in `/measurement.csv`, more are stored but not exported
+pub const MAX_PROBES : usize = 4;
+
+/// the readings of one climate sensor of a [crate::sht::ShtArray] in a blob
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SensorChannel<T> {
+    pub name : String,
+    pub role : crate::Role,
+    pub temperature : T,
+    pub humidity : T,
+}
+
+/// what a power meter measures besides the current
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Power<T> {
+    pub volts : T,
+    pub watts : T,
+    pub energy_wh : T,
+    pub hz : T,
+    pub power_factor : T,
+}
+
+impl<T> Power<T> {
+    fn map<U>(&self, f : impl Fn(&T) -> U) -> Power<U> {
+        Power { volts : f(&self.volts), watts : f(&self.watts), energy_wh : f(&self.energy_wh),
+                hz : f(&self.hz), power_factor : f(&self.power_factor) }
+    }
+}
+
+impl Power<[f32; N1]> {
+    pub fn new() -> Self {
+        Power { volts : [f32::NAN; N1], watts : [f32::NAN; N1], energy_wh : [f32::NAN; N1],
+                hz : [f32::NAN; N1], power_factor : [f32::NAN; N1] }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Meas<T> { 
     pub time : i64,
     /// number of times inside absolute humidity is below the cutoff in this blob
     pub cutoffs : i32,
+    /// number of samples in this blob with a NaN temperature or humidity, 0 in older blobs
+    #[serde(default)]
+    pub climate_errors : i32,
+    /// number of samples in this blob with a NaN current or weight, 0 in older blobs
+    #[serde(default)]
+    pub sensor_errors : i32,
+    /// the sht31 status registers (inside, outside) at the end of this blob, see [crate::hal::sht_flags]
+    #[serde(default)]
+    pub status : [Option<u16>; 2],
+    /// `pressure_kpa` of the config while this blob was measured, 0 (sea level) in older blobs
+    #[serde(default)]
+    pub pressure_kpa : f32,
     pub inside_temp : T,
     pub outside_temp : T,
     pub inside_rh : T,
     pub outside_rh : T,
     pub grams : T,
     pub amps : T,
+    /// 1 when the inside sample was taken with the sht31 heater on, plus 2 for the outside one.
+    /// Missing in older blobs
+    #[serde(default)]
+    pub heater : T,
+    /// amps at the top of the sine, NaN without [crate::rms]. Missing in older blobs
+    #[serde(default)]
+    pub peak_amps : T,
+    /// from a PZEM-004T, None with another current sensor and in older blobs
+    #[serde(default)]
+    pub power : Option<Power<T>>,
+    /// 1 when the weight was stable, see [crate::weigh]. Missing in older blobs
+    #[serde(default)]
+    pub stable : T,
+    /// °C of each food probe, see [crate::probe]. Empty without probes and in older blobs
+    #[serde(default)]
+    pub probes : Vec<T>,
+    /// every climate sensor, of which inside and outside above are made. Empty with only the
+    /// two and in older blobs
+    #[serde(default)]
+    pub sensors : Vec<SensorChannel<T>>,
 }
 
 impl Meas<[f32;N1]> {
@@ -22,26 +92,51 @@ impl Meas<[f32;N1]> {
         Meas {
             time : 0,
             cutoffs : 0,
+            climate_errors : 0,
+            sensor_errors : 0,
+            status : [None, None],
+            pressure_kpa : 0.0,
             inside_temp : [0.0;N1],
             outside_temp : [0.0;N1],
             inside_rh : [0.0;N1],
             outside_rh : [0.0;N1],
             grams : [0.0;N1],
             amps : [0.0;N1],
+            heater : [0.0;N1],
+            peak_amps : [f32::NAN;N1],
+            power : None,
+            stable : [0.0;N1],
+            probes : Vec::new(),
+            sensors : Vec::new(),
         }
     }
 }
 
-pub fn compress(x : Meas<[f32; N1]>) -> Meas<Vec<u8>> {
+pub fn compress(x : &Meas<[f32; N1]>) -> Meas<Vec<u8>> {
         Meas {
             time : x.time,
             cutoffs : x.cutoffs,
+            climate_errors : x.climate_errors,
+            sensor_errors : x.sensor_errors,
+            status : x.status,
+            pressure_kpa : x.pressure_kpa,
             inside_temp : q_compress::auto_compress(&x.inside_temp, 8),
             outside_temp : q_compress::auto_compress(&x.outside_temp, 8),
             inside_rh : q_compress::auto_compress(&x.inside_rh, 8),
             outside_rh : q_compress::auto_compress(&x.outside_rh, 8),
             grams : q_compress::auto_compress(&x.grams, 8),
             amps : q_compress::auto_compress(&x.amps, 8),
+            heater : q_compress::auto_compress(&x.heater, 8),
+            peak_amps : q_compress::auto_compress(&x.peak_amps, 8),
+            power : x.power.as_ref().map(|p| p.map(|c| q_compress::auto_compress(c, 8))),
+            stable : q_compress::auto_compress(&x.stable, 8),
+            probes : x.probes.iter().map(|p| q_compress::auto_compress(p, 8)).collect(),
+            sensors : x.sensors.iter().map(|c| SensorChannel {
+                name : c.name.clone(),
+                role : c.role,
+                temperature : q_compress::auto_compress(&c.temperature, 8),
+                humidity : q_compress::auto_compress(&c.humidity, 8),
+            }).collect(),
         }
     }
 
@@ -49,11 +144,106 @@ pub fn decompress(x : Meas<Vec<u8>>) -> Meas<Vec<f32>> {
         Meas {
             time : x.time,
             cutoffs : x.cutoffs,
+            climate_errors : x.climate_errors,
+            sensor_errors : x.sensor_errors,
+            status : x.status,
+            pressure_kpa : x.pressure_kpa,
             inside_temp : q_compress::auto_decompress(&x.inside_temp).unwrap(),
             outside_temp : q_compress::auto_decompress(&x.outside_temp).unwrap(),
             inside_rh : q_compress::auto_decompress(&x.inside_rh).unwrap(),
             outside_rh : q_compress::auto_decompress(&x.outside_rh).unwrap(),
             grams : q_compress::auto_decompress(&x.grams).unwrap(),
             amps : q_compress::auto_decompress(&x.amps).unwrap(),
+            heater : if x.heater.is_empty() {
+                vec![0.0; N1]
+            } else {
+                q_compress::auto_decompress(&x.heater).unwrap()
+            },
+            peak_amps : if x.peak_amps.is_empty() {
+                vec![f32::NAN; N1]
+            } else {
+                q_compress::auto_decompress(&x.peak_amps).unwrap()
+            },
+            power : x.power.as_ref().map(|p| p.map(|c| q_compress::auto_decompress(c).unwrap())),
+            stable : if x.stable.is_empty() {
+                vec![0.0; N1]
+            } else {
+                q_compress::auto_decompress(&x.stable).unwrap()
+            },
+            probes : x.probes.iter().map(|p| q_compress::auto_decompress(p).unwrap()).collect(),
+            sensors : x.sensors.into_iter().map(|c| SensorChannel {
+                name : c.name,
+                role : c.role,
+                temperature : q_compress::auto_decompress(&c.temperature).unwrap(),
+                humidity : q_compress::auto_decompress(&c.humidity).unwrap(),
+            }).collect(),
+        }
+}
+
+/// first line of `/measurement.csv`
+pub fn write_csv_header(w : &mut dyn std::io::Write) -> std::io::Result<()> {
+    writeln!(w, "j,i,time,i_T,i_RH,o_I,o_RH,amps,grams,heater,i_status,o_status,probe1,probe2,probe3,probe4,stable,peak_amps,volts,watts,energy_wh,hz,power_factor,i_dew,i_vpd,i_W,i_h,i_wb,o_dew,o_vpd,o_W,o_h,o_wb")
+}
+
+/// one line per measurement of blob `j`
+pub fn write_csv_rows(w : &mut dyn std::io::Write, j : &str, b : &Meas<Vec<f32>>) -> std::io::Result<()> {
+    // or use a csv writing library?
+    // like time the status registers are per blob, empty when there aren't any
+    let [i_status, o_status] = b.status.map(|s| s.map_or(String::new(), |s| format!("{:#06x}", s)));
+    for i in 0..b.inside_temp.len() {
+        // an empty cell for a probe that isn't there
+        let probes : Vec<String> = (0..MAX_PROBES)
+            .map(|k| b.probes.get(k).map_or(String::new(), |p| p[i].to_string()))
+            .collect();
+        // empty without a power meter
+        let power = b.power.as_ref().map_or(",,,,".to_string(), |p|
+            format!("{},{},{},{},{}", p.volts[i], p.watts[i], p.energy_wh[i], p.hz[i], p.power_factor[i]));
+        // derived, so they aren't stored
+        let [inside, outside] = [(b.inside_temp[i], b.inside_rh[i]), (b.outside_temp[i], b.outside_rh[i])].map(|(t, rh)| {
+            let p = psychro::psychrometrics(t, rh, b.pressure_kpa);
+            format!("{},{},{},{},{}", p.dew_point, p.vpd_kpa, p.humidity_ratio_g_per_kg, p.enthalpy_kj_per_kg, p.wet_bulb)
+        });
+        writeln!(w, "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+                      j,
+                      i,
+                      b.time, // time is per blob. could be interpolated using i but then
+                              // we need the time from the previous blob or otherwise
+                              // assume a constant time step
+                      b.inside_temp[i],
+                      b.inside_rh[i],
+                      b.outside_temp[i],
+                      b.outside_rh[i],
+                      b.amps[i],
+                      b.grams[i],
+                      b.heater[i],
+                      i_status,
+                      o_status,
+                      probes.join(","),
+                      b.stable[i],
+                      b.peak_amps[i],
+                      power,
+                      inside,
+                      outside)?;
+    }
+    Ok(())
+}
+
+/// first line of `/sensors.csv`
+pub fn write_sensors_csv_header(w : &mut dyn std::io::Write) -> std::io::Result<()> {
+    writeln!(w, "j,i,time,name,role,T,RH")
+}
+
+/// one line per sensor and measurement of blob `j`, nothing for a blob of only inside and outside
+pub fn write_sensors_csv_rows(w : &mut dyn std::io::Write, j : &str, b : &Meas<Vec<f32>>) -> std::io::Result<()> {
+    for i in 0..b.inside_temp.len() {
+        for c in &b.sensors {
+            let role = match c.role {
+                crate::Role::Tray(n) => format!("tray{}", n),
+                crate::Role::Exhaust => "exhaust".to_string(),
+                crate::Role::Ambient => "ambient".to_string(),
+            };
+            writeln!(w, "{},{},{},{},{},{},{}", j, i, b.time, c.name, role, c.temperature[i], c.humidity[i])?;
         }
+    }
+    Ok(())
 }
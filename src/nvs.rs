@@ -1,186 +1,252 @@
-use std::{ptr::null_mut, ffi::CString, mem::transmute, iter::Step, error::Error, fmt::{Display, Formatter}};
+//! Names of the measurement blobs in the "comp" namespace.
+//!
+//! A [Key] is a counter written as [WIDTH] base-36 digits (`0-9a-z`), so every name is
+//! printable, fits the 15 character limit of nvs, and sorts like the number it stands for.
+//! The counter wraps around after [MODULUS] blobs. The oldest blob is the one after the
+//! largest gap between the numbers in use ([in_order]), so the order survives the wraparound
+//! as long as less than the whole range is in flash at once.
 
-use esp_idf_svc::nvs::{EspNvs, NvsCustom, NvsDefault};
-use esp_idf_sys::{nvs_entry_find, nvs_type_t_NVS_TYPE_BLOB, nvs_entry_info_t, nvs_entry_info, nvs_entry_next, EspError};
+use crate::store::BlobStore;
 
-#[derive(Clone,Copy,PartialOrd,PartialEq)]
-pub struct Key ([u8; 16]);
+/// digits in a key
+pub const WIDTH : usize = 8;
+
+/// number of distinct keys: 36^8, about 2.8e12
+pub const MODULUS : u64 = 36u64.pow(WIDTH as u32);
+
+const DIGITS : &[u8; 36] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Key (u64);
 
 impl Key {
-    pub fn get_first_comp() -> Key {
-        unsafe {
-            let iter = null_mut();
-            let partition = CString::new("measured").unwrap();
-            let namespace = CString::new("comp").unwrap();
-
-            nvs_entry_find(partition.as_ptr(),
-                    namespace.as_ptr(),
-                    nvs_type_t_NVS_TYPE_BLOB,
-                    iter);
-            let mut info : nvs_entry_info_t = Default::default();
-            nvs_entry_info(*iter, &mut info as *mut _); 
-            return Key(transmute(info.key));
-        }
+    pub fn new(n : u64) -> Key {
+        Key(n % MODULUS)
     }
-    pub fn get_last_comp() -> Key {
-        unsafe {
-            let iter = null_mut();
-            let partition = CString::new("measured").unwrap();
-            let namespace = CString::new("comp").unwrap();
-
-            nvs_entry_find(partition.as_ptr(),
-                    namespace.as_ptr(),
-                    nvs_type_t_NVS_TYPE_BLOB,
-                    iter);
-            let mut info : nvs_entry_info_t = Default::default();
-            while iter != null_mut() {
-                nvs_entry_info(*iter, &mut info as *mut _); 
-                nvs_entry_next(iter);
-            }
-            return Key(transmute(info.key));
+
+    /// None unless `s` is exactly [WIDTH] digits of `0-9a-z`, which skips the binary
+    /// names older firmware wrote
+    pub fn parse(s : &str) -> Option<Key> {
+        if s.len() != WIDTH {
+            return None;
+        }
+        let mut n = 0u64;
+        for c in s.bytes() {
+            let d = DIGITS.iter().position(|&x| x == c)? as u64;
+            n = n * 36 + d;
         }
+        Some(Key(n))
     }
 
-    pub fn to_str(&self) -> &str {
-        unsafe { std::str::from_utf8_unchecked(&self.0) }
+    pub fn to_u64(&self) -> u64 {
+        self.0
     }
 
-    // reversed keys are ordered lexicographically
-    // interpreted as u8 (c says it's an i8)
     pub fn next(&mut self) {
-        for i in 0..16 {
-            self.0[i] += 1;
-            if self.0[i] != 0 { break }
-        }
-    }
-    fn prev(&mut self) {
-        for i in 0..16 {
-            self.0[i] -= 1;
-            if self.0[i] != 255 { break }
-        }
+        self.0 = (self.0 + 1) % MODULUS;
     }
 
-    fn subtract(&mut self, other : &Key) {
-        let mut borrow = 0;
-        for i in 0..16 {
-            let mut diff = self.0[i] as i16 - other.0[i] as i16 - borrow;
-            if diff < 0 {
-                diff += 256;
-                borrow = 1;
-            } else {
-                borrow = 0;
-            }
-            self.0[i] = diff as u8;
-        }
+    pub fn prev(&mut self) {
+        self.0 = (self.0 + MODULUS - 1) % MODULUS;
     }
 
-    fn to_usize(&self) -> Option<usize> {
-        let mut ret = 0usize;
-        for i in 0..16 {
-            // break if we would have an overflow
-            ret = ret.checked_mul(256usize)?.checked_add(self.0[i] as usize)?;
-        }
-        Some(ret)
+    /// how many times [Key::next] gets from `self` to `other`
+    pub fn steps_to(&self, other : &Key) -> u64 {
+        (other.0 + MODULUS - self.0) % MODULUS
     }
-
 }
 
-// enable k1 .. k2 syntax
-impl Step for Key {
-    fn forward_checked(start: Self, count: usize) -> Option<Self> {
-        let mut ret = start;
-        for _ in 0..count {
-            ret.next();
+impl std::fmt::Display for Key {
+    fn fmt(&self, f : &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut buf = [b'0'; WIDTH];
+        let mut n = self.0;
+        for c in buf.iter_mut().rev() {
+            *c = DIGITS[(n % 36) as usize];
+            n /= 36;
         }
-        Some(ret)
-    }
-
-    // I don't use these
-    fn backward_checked(start: Self, count: usize) -> Option<Self> {
-        let mut ret = start;
-        for _ in 0..count {
-            ret.prev();
-        }
-        Some(ret)
-    }
-    fn steps_between(start: &Self, end: &Self) -> Option<usize> {
-        let mut s = start.clone();
-        s.subtract(end);
-        s.to_usize()
+        f.write_str(std::str::from_utf8(&buf).unwrap())
     }
 }
 
+/// The keys oldest first. Sorted numerically the list is split at the largest gap between
+/// neighbours (counting the gap from the last back around to the first), and the key after
+/// that gap is the oldest.
+pub fn in_order(mut keys : Vec<Key>) -> Vec<Key> {
+    keys.sort();
+    keys.dedup();
+    if keys.len() < 2 {
+        return keys;
+    }
+    let n = keys.len();
+    // gap before keys[i]
+    let oldest = (0..n)
+        .max_by_key(|&i| keys[(i + n - 1) % n].steps_to(&keys[i]))
+        .unwrap_or(0);
+    keys.rotate_left(oldest);
+    keys
+}
 
-/// A reader/writer for ciborium that uses esp-idf nvs.
-/// This allows ciborium::de::from_reader and ciborium::ser::to_writer
-/// to call [esp_idf_svc::nvs::EspNvs::set_blob]
-/// and     [esp_idf_svc::nvs::EspNvs::get_blob]
-pub struct ReadWrite<'a> (pub &'a mut EspNvs<NvsCustom>, pub &'a mut Key);
-
-impl <'a> ciborium_io::Write for ReadWrite<'a> {
-    type Error = EspError;
-    fn write_all(&mut self, buf: &[u8]) -> Result<(), Self::Error> {
-        self.0.set_blob(self.1.to_str(), buf)?;
-        Ok(())
+/// the key to write after the newest of `keys`, 0 for an empty namespace
+pub fn next_key(keys : &[Key]) -> Key {
+    match in_order(keys.to_vec()).last() {
+        Some(&k) => { let mut k = k; k.next(); k },
+        None => Key(0),
     }
+}
 
-    /// noop because set_blob calls nvs_commit
-    fn flush(&mut self) -> Result<(), Self::Error> {
-        Ok(())
-    }
+/// Names of the blobs in a namespace, wrapping `nvs_entry_find` so that the iterator
+/// is advanced through a valid pointer and released when dropped. See [crate::store::NvsStore].
+#[cfg(feature = "esp")]
+pub struct Entries {
+    it : esp_idf_sys::nvs_iterator_t,
+    started : bool,
 }
 
-impl <'a> ciborium_io::Read for ReadWrite<'a> {
-    type Error = EspError;
-    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), Self::Error> {
-        let _buf = self.0.get_blob(self.1.to_str(), buf)?.expect("empty blob");
-        Ok(())
+#[cfg(feature = "esp")]
+impl Entries {
+    pub fn new(partition : &str, namespace : &str) -> anyhow::Result<Self> {
+        use std::ffi::CString;
+        let partition = CString::new(partition)?;
+        let namespace = CString::new(namespace)?;
+        let mut it : esp_idf_sys::nvs_iterator_t = std::ptr::null_mut();
+        let err = unsafe {
+            esp_idf_sys::nvs_entry_find(partition.as_ptr(),
+                                        namespace.as_ptr(),
+                                        esp_idf_sys::nvs_type_t_NVS_TYPE_BLOB,
+                                        &mut it)
+        };
+        // not found leaves `it` null, which is an empty iterator
+        if err != esp_idf_sys::ESP_OK && err != esp_idf_sys::ESP_ERR_NVS_NOT_FOUND {
+            esp_idf_sys::esp!(err)?;
+        }
+        Ok(Entries { it, started : false })
     }
 }
 
-/// see [ReadWrite]. There are two difference. First the key is a string slice,
-/// so there is no way to "get the first/last/next key". And the NvsDefault partition
-/// is used which is the first partition in `partitions.csv`
-pub struct ReadWriteStr<'a> (pub &'a mut EspNvs<NvsDefault>, pub &'a str);
+#[cfg(feature = "esp")]
+impl Iterator for Entries {
+    type Item = String;
 
-impl <'a> ciborium_io::Write for ReadWriteStr<'a> {
-    type Error = EspError;
-    fn write_all(&mut self, buf: &[u8]) -> Result<(), Self::Error> {
-        self.0.set_blob(self.1, buf)?;
-        Ok(())
+    fn next(&mut self) -> Option<String> {
+        if self.it.is_null() {
+            return None;
+        }
+        if self.started {
+            // sets `it` to null (and releases it) after the last entry
+            let err = unsafe { esp_idf_sys::nvs_entry_next(&mut self.it) };
+            if err != esp_idf_sys::ESP_OK || self.it.is_null() {
+                return None;
+            }
+        }
+        self.started = true;
+        let mut info : esp_idf_sys::nvs_entry_info_t = Default::default();
+        unsafe { esp_idf_sys::nvs_entry_info(self.it, &mut info) };
+        let name = unsafe { std::ffi::CStr::from_ptr(info.key.as_ptr()) };
+        Some(name.to_string_lossy().into_owned())
     }
+}
 
-    /// noop because set_blob calls nvs_commit
-    fn flush(&mut self) -> Result<(), Self::Error> {
-        Ok(())
+#[cfg(feature = "esp")]
+impl Drop for Entries {
+    fn drop(&mut self) {
+        unsafe { esp_idf_sys::nvs_release_iterator(self.it) };
     }
 }
-impl <'a> ciborium_io::Read for ReadWriteStr<'a> {
-    type Error = anyhow::Error;
-    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), Self::Error> {
-        let _buf = self.0.get_blob(self.1, buf)?.ok_or(anyhow::Error::new(EmptyBlob))?;
-        Ok(())
-    }
+
+/// the measurement blobs in `store` oldest first
+pub fn comp_keys(store : &(impl BlobStore + ?Sized)) -> anyhow::Result<Vec<Key>> {
+    Ok(in_order(store.keys()?.iter().filter_map(|k| Key::parse(k)).collect()))
 }
 
-/// copy pasted except for reference
-impl <'a> ciborium_io::Read for &ReadWriteStr<'a> {
-    type Error = anyhow::Error;
-    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), Self::Error> {
-        let _buf = self.0.get_blob(self.1, buf)?.ok_or(anyhow::Error::new(EmptyBlob))?;
-        Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    fn keys(ns : &[u64]) -> Vec<Key> {
+        ns.iter().map(|&n| Key::new(n)).collect()
     }
-}
 
-#[derive(Debug)]
-pub struct EmptyBlob;
-impl Display for EmptyBlob {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "empty blob")
+    #[test]
+    fn fixed_width_round_trip() {
+        for n in [0, 1, 35, 36, 36 * 36 - 1, 123_456_789, MODULUS - 1] {
+            let s = Key::new(n).to_string();
+            assert_eq!(s.len(), WIDTH);
+            assert_eq!(Key::parse(&s), Some(Key::new(n)), "{}", s);
+        }
+        assert_eq!(Key::new(0).to_string(), "00000000");
+        assert_eq!(Key::new(36).to_string(), "00000010");
+        assert_eq!(Key::new(MODULUS - 1).to_string(), "zzzzzzzz");
+        assert_eq!(Key::new(MODULUS), Key::new(0));
+        // names that aren't keys: too short or long, upper case, binary
+        for s in ["0000000", "000000000", "0000000A", "0000000-", "\u{1}\u{0}\u{0}\u{0}\u{0}\u{0}\u{0}\u{0}", "auth"] {
+            assert_eq!(Key::parse(s), None, "{:?}", s);
+        }
     }
-}
-impl Error for EmptyBlob {
-    fn source(&self) -> Option<&(dyn Error + 'static)> {
-        None
+
+    #[test]
+    fn strings_sort_like_numbers() {
+        let mut ks = keys(&[1_000_000, 5, 36, 35, 0, 1296]);
+        let mut names : Vec<String> = ks.iter().map(Key::to_string).collect();
+        ks.sort();
+        names.sort();
+        assert_eq!(names, ks.iter().map(Key::to_string).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn next_and_prev_wrap() {
+        let mut k = Key::new(MODULUS - 1);
+        k.next();
+        assert_eq!(k, Key::new(0));
+        k.prev();
+        assert_eq!(k, Key::new(MODULUS - 1));
+        assert_eq!(Key::new(MODULUS - 2).steps_to(&Key::new(3)), 5);
+        assert_eq!(Key::new(3).steps_to(&Key::new(3)), 0);
+    }
+
+    #[test]
+    fn order_across_the_wrap() {
+        // written MODULUS - 3 .. MODULUS - 1, then 0 .. 2
+        let written = [MODULUS - 3, MODULUS - 2, MODULUS - 1, 0, 1, 2];
+        let mut shuffled = keys(&[1, MODULUS - 1, 2, MODULUS - 3, 0, MODULUS - 2]);
+        shuffled.push(Key::new(0));
+        assert_eq!(in_order(shuffled.clone()), keys(&written));
+        assert_eq!(next_key(&shuffled), Key::new(3));
+    }
+
+    #[test]
+    fn oldest_is_after_the_largest_gap() {
+        // the gap back around from 3 to 14 is by far the largest
+        assert_eq!(in_order(keys(&[20, 14, 15, 3, 16])), keys(&[3, 14, 15, 16, 20]));
+        // here it is the one in the middle of the range
+        let half = MODULUS / 2;
+        assert_eq!(in_order(keys(&[0, MODULUS - 1, half + 1, half])), keys(&[half, half + 1, MODULUS - 1, 0]));
+        // no wrap: the largest gap is from the last back around to the first
+        assert_eq!(in_order(keys(&[7, 5, 6])), keys(&[5, 6, 7]));
+        assert_eq!(in_order(keys(&[9])), keys(&[9]));
+        assert_eq!(in_order(Vec::new()), Vec::new());
+    }
+
+    #[test]
+    fn next_key_of_an_empty_namespace() {
+        assert_eq!(next_key(&[]), Key::new(0));
+        assert_eq!(next_key(&keys(&[4, 2, 3])), Key::new(5));
+    }
+
+    #[test]
+    fn comp_keys_of_a_store() {
+        let mut store = MemoryStore::default();
+        assert!(comp_keys(&store).unwrap().is_empty());
+        for n in [MODULUS - 2, MODULUS - 1, 0, 1] {
+            store.set_blob(&Key::new(n).to_string(), &[1]).unwrap();
+        }
+        // other blobs in the namespace are skipped
+        store.set_blob("stepper_calib", &[2]).unwrap();
+        store.set_blob("0000001", &[3]).unwrap();
+        assert_eq!(comp_keys(&store).unwrap(), keys(&[MODULUS - 2, MODULUS - 1, 0, 1]));
+
+        store.erase(&Key::new(MODULUS - 2).to_string()).unwrap();
+        assert_eq!(comp_keys(&store).unwrap(), keys(&[MODULUS - 1, 0, 1]));
+        assert_eq!(next_key(&comp_keys(&store).unwrap()), Key::new(2));
     }
 }
@@ -0,0 +1,349 @@
+//! Food probes, for the temperature of the product rather than the air: DS18B20s on a
+//! 1-Wire bus. Decoding what the chips send is done here for any target; the driver in
+//! `esp` only moves the bits.
+//!
+//! There are no thermocouples (MAX31855, MAX6675): they need an spi bus of their own, and
+//! the esp32c3 has one for general use, whose MISO the HX711's DT drives all the time.
+//!
+//! Several probes are a `Vec<Box<dyn Probes + Send>>`, and a single 1-Wire bus can carry
+//! any number of DS18B20s.
+
+use anyhow::anyhow;
+
+use crate::hal::Probes;
+
+/// what a DS18B20 reads before its first conversion
+pub const DS18B20_POWER_ON : f32 = 85.0;
+
+/// µs from the start of a read slot past which the line is still low when a device sends
+/// 0, it holds it for at least 15 µs while the master's own pulse is 6 µs
+const READ_ZERO_US : u16 = 15;
+/// shortest presence pulse after a reset, the datasheet's is 60 to 240 µs
+const PRESENCE_US : u16 = 50;
+
+/// whether a device answered a reset, from the low pulses in µs on the line: the master's
+/// reset pulse and then a presence pulse
+pub fn presence(lows_us : &[u16]) -> bool {
+    lows_us.iter().skip(1).any(|&l| l >= PRESENCE_US)
+}
+
+/// the bits of `n` read slots from the low pulses in µs on the line, one per slot, None
+/// when there aren't `n` of them
+pub fn read_slots(lows_us : &[u16], n : usize) -> Option<Vec<bool>> {
+    if lows_us.len() != n {
+        return None;
+    }
+    Some(lows_us.iter().map(|&l| l < READ_ZERO_US).collect())
+}
+
+/// Maxim's 1-Wire crc: polynomial x^8+x^5+x^4+1, lsb first, 0 over data followed by its crc
+pub fn crc8_maxim(data : &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &b in data {
+        let mut b = b;
+        for _ in 0..8 {
+            let mix = (crc ^ b) & 1;
+            crc >>= 1;
+            if mix != 0 {
+                crc ^= 0x8C;
+            }
+            b >>= 1;
+        }
+    }
+    crc
+}
+
+/// °C from the 9 byte scratchpad of a DS18B20 at 12 bit resolution
+pub fn ds18b20_celsius(scratchpad : &[u8; 9]) -> anyhow::Result<f32> {
+    if crc8_maxim(scratchpad) != 0 {
+        return Err(anyhow!("ds18b20 scratchpad crc {:02x?}", scratchpad));
+    }
+    Ok(i16::from_le_bytes([scratchpad[0], scratchpad[1]]) as f32 / 16.0)
+}
+
+/// all the probes one after the other, a failing one is NaN
+impl Probes for Vec<Box<dyn Probes + Send>> {
+    fn read(&mut self) -> anyhow::Result<Vec<f32>> {
+        let mut t = Vec::new();
+        for p in self.iter_mut() {
+            match p.read() {
+                Ok(x) => t.extend(x),
+                Err(e) => {
+                    log::warn!("probe: {:?}", e);
+                    t.push(f32::NAN);
+                },
+            }
+        }
+        Ok(t)
+    }
+}
+
+#[cfg(feature = "esp")]
+pub use esp::{Ds18b20s, OneWire};
+
+#[cfg(feature = "esp")]
+mod esp {
+    use anyhow::anyhow;
+    use esp_idf_hal::{gpio::{AnyIOPin, Pin}, peripheral::Peripheral,
+                      rmt::{Pulse, PulseTicks, PinState, Receive, RmtChannel, RmtReceiveConfig, RmtTransmitConfig, RxRmtDriver, TxRmtDriver,
+                            VariableLengthSignal}};
+    use log::*;
+
+    use crate::hal::Probes;
+    use super::{ds18b20_celsius, crc8_maxim, presence, read_slots, DS18B20_POWER_ON};
+
+    const SEARCH_ROM : u8 = 0xF0;
+    const MATCH_ROM : u8 = 0x55;
+    const SKIP_ROM : u8 = 0xCC;
+    const CONVERT_T : u8 = 0x44;
+    const READ_SCRATCHPAD : u8 = 0xBE;
+    /// 12 bit conversion time
+    const CONVERSION_MS : u32 = 750;
+
+    /// A 1-Wire bus on one open drain pin with a 4.7 kΩ pull-up, timed by the RMT rather
+    /// than the cpu, so neither wifi interrupts nor the scheduler can stretch a slot. A tx
+    /// channel sends the slots and an rx channel on the same pin records the line, with the
+    /// devices' pulses, at 1 µs per tick. A frame is one reset or one byte, it ends when
+    /// the line has been idle for [IDLE_US].
+    pub struct OneWire<'d> {
+        tx : TxRmtDriver<'d>,
+        rx : RxRmtDriver<'d>,
+        items : Vec<(Pulse, Pulse)>,
+    }
+
+    /// longer than any time without an edge within a frame, the 480 µs reset pulse
+    const IDLE_US : u16 = 600;
+    /// rx items a frame may take, the channel's memory holds 48
+    const MAX_ITEMS : usize = 48;
+
+    fn low(us : u16) -> anyhow::Result<Pulse> {
+        Ok(Pulse::new(PinState::Low, PulseTicks::new(us)?))
+    }
+
+    fn high(us : u16) -> anyhow::Result<Pulse> {
+        Ok(Pulse::new(PinState::High, PulseTicks::new(us)?))
+    }
+
+    /// a write slot, which is also a read slot when `bit` is 1
+    fn slot(bit : bool) -> anyhow::Result<[Pulse; 2]> {
+        Ok(if bit { [low(6)?, high(64)?] } else { [low(60)?, high(10)?] })
+    }
+
+    impl<'d> OneWire<'d> {
+        /// `tx` is a channel that can send (0 and 1 on the esp32c3, 0 is the ir shutdown) and
+        /// `rx` one that can receive (2 and 3)
+        pub fn new(pin : AnyIOPin,
+                   tx : impl Peripheral<P = impl RmtChannel> + 'd,
+                   rx : impl Peripheral<P = impl RmtChannel> + 'd) -> anyhow::Result<Self> {
+            let gpio = pin.pin();
+            // both drivers are on the pin, which only the rx one reads
+            let rx_pin = unsafe { pin.clone_unchecked() };
+            let rx = RxRmtDriver::new(rx, rx_pin,
+                                      &RmtReceiveConfig::new().clock_divider(80).idle_threshold(IDLE_US)
+                                          .filter_en(true).filter_ticks_thresh(80),
+                                      1000)?;
+            // released between frames
+            let tx = TxRmtDriver::new(tx, pin, &RmtTransmitConfig::new().clock_divider(80).idle(Some(PinState::High)))?;
+            // the tx driver made it push-pull, the devices have to pull it low too
+            esp_idf_sys::esp!(unsafe { esp_idf_sys::gpio_set_direction(gpio, esp_idf_sys::gpio_mode_t_GPIO_MODE_INPUT_OUTPUT_OD) })?;
+            Ok(OneWire { tx, rx, items : vec![(Pulse::zero(), Pulse::zero()); MAX_ITEMS] })
+        }
+
+        /// send `pulses` as one frame and give the lengths in µs of the low pulses on the line
+        fn frame(&mut self, pulses : &[Pulse]) -> anyhow::Result<Vec<u16>> {
+            let mut signal = VariableLengthSignal::new();
+            signal.push(pulses)?;
+            self.rx.start()?;
+            let sent = self.tx.start_blocking(&signal);
+            // a frame is over within a few ms, this is more than one FreeRTOS tick
+            let received = sent.and_then(|()| self.rx.receive(&mut self.items, 2));
+            self.rx.stop()?;
+            let n = match received? {
+                Receive::Read(n) => n,
+                Receive::Overflow(_) => return Err(anyhow!("1-wire: more than {} pulses", 2 * MAX_ITEMS)),
+                Receive::Timeout => return Err(anyhow!("1-wire: the rmt didn't see the frame")),
+            };
+            Ok(self.items[..n].iter()
+                .flat_map(|(a, b)| [a, b])
+                .filter(|p| matches!(p.pin_state, PinState::Low) && p.ticks.ticks() > 0)
+                .map(|p| p.ticks.ticks())
+                .collect())
+        }
+
+        /// true when some device answered with a presence pulse
+        fn reset(&mut self) -> anyhow::Result<bool> {
+            Ok(presence(&self.frame(&[low(480)?, high(410)?])?))
+        }
+
+        fn write_bits(&mut self, bits : impl IntoIterator<Item = bool>) -> anyhow::Result<()> {
+            let mut pulses = Vec::new();
+            for bit in bits {
+                pulses.extend(slot(bit)?);
+            }
+            self.frame(&pulses)?;
+            Ok(())
+        }
+
+        fn read_bits(&mut self, n : usize) -> anyhow::Result<Vec<bool>> {
+            let slots : Vec<Pulse> = (0..n).map(|_| slot(true)).collect::<anyhow::Result<Vec<_>>>()?.concat();
+            let lows = self.frame(&slots)?;
+            read_slots(&lows, n).ok_or_else(|| anyhow!("1-wire: {} read slots gave {} low pulses", n, lows.len()))
+        }
+
+        fn write_byte(&mut self, b : u8) -> anyhow::Result<()> {
+            self.write_bits((0..8).map(|i| b & (1 << i) != 0))
+        }
+
+        fn read_byte(&mut self) -> anyhow::Result<u8> {
+            Ok(self.read_bits(8)?.iter().enumerate().fold(0, |b, (i, &bit)| if bit { b | 1 << i } else { b }))
+        }
+
+        /// the 64 bit roms of every device on the bus (Maxim application note 187)
+        pub fn search(&mut self) -> anyhow::Result<Vec<[u8; 8]>> {
+            let mut roms = Vec::new();
+            let mut rom = [0u8; 8];
+            // bit index of the last branch where 0 was taken, 0 when there is none left
+            let mut last_discrepancy = 0;
+            loop {
+                if !self.reset()? {
+                    return Ok(roms);
+                }
+                self.write_byte(SEARCH_ROM)?;
+                let mut discrepancy = 0;
+                for i in 1..=64 {
+                    let (byte, mask) = ((i - 1) / 8, 1u8 << ((i - 1) % 8));
+                    let pair = self.read_bits(2)?;
+                    let take = match (pair[0], pair[1]) {
+                        (true, true) => return Err(anyhow!("1-wire: device left during search")),
+                        (b, c) if b != c => b,
+                        // devices differ here
+                        _ => {
+                            let take = if i < last_discrepancy { rom[byte] & mask != 0 } else { i == last_discrepancy };
+                            if !take {
+                                discrepancy = i;
+                            }
+                            take
+                        },
+                    };
+                    if take { rom[byte] |= mask } else { rom[byte] &= !mask }
+                    self.write_bits([take])?;
+                }
+                if crc8_maxim(&rom) != 0 {
+                    return Err(anyhow!("1-wire: rom crc {:02x?}", rom));
+                }
+                roms.push(rom);
+                last_discrepancy = discrepancy;
+                if last_discrepancy == 0 {
+                    return Ok(roms);
+                }
+            }
+        }
+    }
+
+    /// every DS18B20 found on a bus at startup. A conversion is started after each read so
+    /// that the next read doesn't wait for it.
+    pub struct Ds18b20s<'d> {
+        bus : OneWire<'d>,
+        roms : Vec<[u8; 8]>,
+        converting : bool,
+    }
+
+    /// family code of the DS18B20
+    const DS18B20 : u8 = 0x28;
+
+    impl<'d> Ds18b20s<'d> {
+        pub fn new(mut bus : OneWire<'d>) -> anyhow::Result<Self> {
+            let roms : Vec<[u8; 8]> = bus.search()?.into_iter().filter(|r| r[0] == DS18B20).collect();
+            info!("{} ds18b20 probes: {:02x?}", roms.len(), roms);
+            Ok(Ds18b20s { bus, roms, converting : false })
+        }
+
+        fn convert(&mut self) -> anyhow::Result<()> {
+            if !self.bus.reset()? {
+                return Err(anyhow!("1-wire: no presence pulse"));
+            }
+            self.bus.write_byte(SKIP_ROM)?;
+            self.bus.write_byte(CONVERT_T)?;
+            Ok(())
+        }
+
+        fn read_one(&mut self, k : usize) -> anyhow::Result<f32> {
+            if !self.bus.reset()? {
+                return Err(anyhow!("1-wire: no presence pulse"));
+            }
+            self.bus.write_byte(MATCH_ROM)?;
+            for b in self.roms[k] {
+                self.bus.write_byte(b)?;
+            }
+            self.bus.write_byte(READ_SCRATCHPAD)?;
+            let mut scratchpad = [0u8; 9];
+            for b in scratchpad.iter_mut() {
+                *b = self.bus.read_byte()?;
+            }
+            ds18b20_celsius(&scratchpad)
+        }
+    }
+
+    impl<'d> Probes for Ds18b20s<'d> {
+        fn read(&mut self) -> anyhow::Result<Vec<f32>> {
+            // a bus error leaves every probe NaN rather than the Vec impl's single one
+            if !self.converting && self.convert().is_ok() {
+                std::thread::sleep(std::time::Duration::from_millis(CONVERSION_MS as u64));
+            }
+            let t = (0..self.roms.len())
+                .map(|k| match self.read_one(k) {
+                    Ok(t) if t != DS18B20_POWER_ON => t,
+                    Ok(_) => f32::NAN,
+                    Err(e) => {
+                        warn!("{:?}", e);
+                        f32::NAN
+                    },
+                })
+                .collect();
+            self.converting = self.convert().is_ok();
+            Ok(t)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc_of_a_rom() {
+        // Maxim application note 27
+        let rom = [0x02, 0x1C, 0xB8, 0x01, 0x00, 0x00, 0x00, 0xA2];
+        assert_eq!(crc8_maxim(&rom[..7]), 0xA2);
+        assert_eq!(crc8_maxim(&rom), 0);
+        assert_ne!(crc8_maxim(&[0x02, 0x1C, 0xB8, 0x01, 0x00, 0x00, 0x01, 0xA2]), 0);
+    }
+
+    fn scratchpad(lsb : u8, msb : u8, crc : u8) -> [u8; 9] {
+        [lsb, msb, 0x4B, 0x46, 0x7F, 0xFF, 0x0C, 0x10, crc]
+    }
+
+    #[test]
+    fn ds18b20_scratchpads() {
+        assert_eq!(ds18b20_celsius(&scratchpad(0x50, 0x05, 0x1C)).unwrap(), DS18B20_POWER_ON);
+        // datasheet table 1
+        assert_eq!(ds18b20_celsius(&scratchpad(0x91, 0x01, 0x70)).unwrap(), 25.0625);
+        assert_eq!(ds18b20_celsius(&scratchpad(0x5E, 0xFF, 0x6A)).unwrap(), -10.125);
+        assert_eq!(ds18b20_celsius(&scratchpad(0x90, 0xFC, 0x4F)).unwrap(), -55.0);
+        // a flipped bit, and a bus where nobody answered
+        assert!(ds18b20_celsius(&scratchpad(0x91, 0x03, 0x70)).is_err());
+        assert!(ds18b20_celsius(&[0xFF; 9]).is_err());
+    }
+
+    #[test]
+    fn slots_from_the_line() {
+        // the master's 6 µs, and a device holding the line for 0
+        assert_eq!(read_slots(&[6, 30, 7, 6, 45, 6, 6, 28], 8).unwrap(),
+                   [true, false, true, true, false, true, true, false]);
+        assert_eq!(read_slots(&[6, 30], 3), None);
+        assert!(presence(&[480, 120]));
+        assert!(!presence(&[480]));
+        // a glitch is no presence pulse
+        assert!(!presence(&[480, 3]));
+    }
+}
@@ -0,0 +1,174 @@
+//! Moist air from a temperature and relative humidity, for the air in the dehydrator and
+//! the room. Saturation over water follows Buck (1996), which stays within 0.1 % up to
+//! 100 °C, and the rest are the ASHRAE Fundamentals (2017, ch. 1) relations at a given
+//! barometric pressure. NaN in gives NaN out.
+
+use crate::Psychrometrics;
+
+/// sea level, what `pressure_kpa` 0 in the config means
+pub const STANDARD_KPA : f32 = 101.325;
+
+/// ratio of the molar masses of water and dry air
+const EPSILON : f64 = 0.621945;
+
+// Buck (1996) over water: kPa = A exp((B - T/D) T/(C + T))
+const A : f64 = 0.61121;
+const B : f64 = 18.678;
+const C : f64 = 257.14;
+const D : f64 = 234.5;
+
+/// saturation vapor pressure over water in kPa
+pub fn saturation_kpa(temp_celsius : f32) -> f32 {
+    let t = temp_celsius as f64;
+    (A * ((B - t / D) * t / (C + t)).exp()) as f32
+}
+
+/// partial pressure of the water vapor in kPa
+pub fn vapor_pressure_kpa(temp_celsius : f32, rh_percent : f32) -> f32 {
+    saturation_kpa(temp_celsius) * rh_percent / 100.0
+}
+
+/// °C at which the vapor pressure `e_kpa` saturates, Buck's equation solved for T
+pub fn dew_point_from_kpa(e_kpa : f32) -> f32 {
+    // (B - T/D) T/(C + T) = L is T²/D + (L - B) T + L C = 0, the smaller root
+    let l = (e_kpa as f64 / A).ln();
+    let b = B - l;
+    (D / 2.0 * (b - (b * b - 4.0 * l * C / D).sqrt())) as f32
+}
+
+pub fn dew_point(temp_celsius : f32, rh_percent : f32) -> f32 {
+    dew_point_from_kpa(vapor_pressure_kpa(temp_celsius, rh_percent))
+}
+
+/// vapor pressure deficit in kPa: how much more water the air could take up
+pub fn vpd_kpa(temp_celsius : f32, rh_percent : f32) -> f32 {
+    saturation_kpa(temp_celsius) * (1.0 - rh_percent / 100.0)
+}
+
+/// kg of water per kg of dry air
+fn ratio(e_kpa : f64, pressure_kpa : f64) -> f64 {
+    EPSILON * e_kpa / (pressure_kpa - e_kpa)
+}
+
+/// g of water per kg of dry air
+pub fn humidity_ratio_g_per_kg(temp_celsius : f32, rh_percent : f32, pressure_kpa : f32) -> f32 {
+    (1000.0 * ratio(vapor_pressure_kpa(temp_celsius, rh_percent) as f64, pressure_kpa as f64)) as f32
+}
+
+/// kJ per kg of dry air, 0 for dry air at 0 °C
+pub fn enthalpy_kj_per_kg(temp_celsius : f32, rh_percent : f32, pressure_kpa : f32) -> f32 {
+    let t = temp_celsius as f64;
+    let w = ratio(vapor_pressure_kpa(temp_celsius, rh_percent) as f64, pressure_kpa as f64);
+    (1.006 * t + w * (2501.0 + 1.86 * t)) as f32
+}
+
+/// °C below which [wet_bulb] doesn't look, far below that of any air at room temperature
+const MIN_WET_BULB : f64 = -60.0;
+
+/// thermodynamic wet bulb °C: the temperature at which evaporating water saturates the air
+/// at constant enthalpy. The humidity ratio that a wet bulb temperature implies rises with
+/// it, so it is bisected between the dew point and the dry bulb.
+pub fn wet_bulb(temp_celsius : f32, rh_percent : f32, pressure_kpa : f32) -> f32 {
+    let t = temp_celsius as f64;
+    let p = pressure_kpa as f64;
+    let w = ratio(vapor_pressure_kpa(temp_celsius, rh_percent) as f64, p);
+    // ASHRAE eq. 33, above freezing
+    let implied = |twb : f64| {
+        let ws = ratio(saturation_kpa(twb as f32) as f64, p);
+        ((2501.0 - 2.326 * twb) * ws - 1.006 * (t - twb)) / (2501.0 + 1.86 * t - 4.186 * twb)
+    };
+    // dry air has no dew point
+    let (mut lo, mut hi) = ((dew_point(temp_celsius, rh_percent) as f64 - 1.0).max(MIN_WET_BULB), t);
+    if !(w.is_finite() && hi.is_finite()) {
+        return f32::NAN;
+    }
+    for _ in 0..40 {
+        let mid = (lo + hi) / 2.0;
+        if implied(mid) < w {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    ((lo + hi) / 2.0) as f32
+}
+
+/// all of the above, at [STANDARD_KPA] when `pressure_kpa` is 0
+pub fn psychrometrics(temp_celsius : f32, rh_percent : f32, pressure_kpa : f32) -> Psychrometrics {
+    let p = if pressure_kpa > 0.0 { pressure_kpa } else { STANDARD_KPA };
+    Psychrometrics {
+        dew_point : dew_point(temp_celsius, rh_percent),
+        vpd_kpa : vpd_kpa(temp_celsius, rh_percent),
+        humidity_ratio_g_per_kg : humidity_ratio_g_per_kg(temp_celsius, rh_percent, p),
+        enthalpy_kj_per_kg : enthalpy_kj_per_kg(temp_celsius, rh_percent, p),
+        wet_bulb : wet_bulb(temp_celsius, rh_percent, p),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(x : f32, y : f32, tol : f32) {
+        assert!((x - y).abs() <= tol, "{} is not {} ± {}", x, y, tol);
+    }
+
+    #[test]
+    fn saturation() {
+        // ASHRAE Fundamentals (2017) ch. 1 table 3
+        close(saturation_kpa(0.0), 0.6112, 0.001);
+        close(saturation_kpa(20.0), 2.3392, 0.002);
+        close(saturation_kpa(25.0), 3.1699, 0.003);
+        close(saturation_kpa(60.0), 19.946, 0.02);
+        close(saturation_kpa(100.0), 101.42, 0.15);
+    }
+
+    #[test]
+    fn dew_point_inverts_saturation() {
+        close(dew_point(20.0, 100.0), 20.0, 0.01);
+        close(dew_point(25.0, 50.0), 13.86, 0.02);
+        close(dew_point(60.0, 20.0), 28.92, 0.02);
+        close(dew_point(5.0, 80.0), 1.84, 0.02);
+        for t in [-10.0, 0.0, 37.0, 70.0] {
+            close(dew_point_from_kpa(saturation_kpa(t)), t, 0.01);
+        }
+        assert!(!dew_point(25.0, 0.0).is_finite());
+    }
+
+    #[test]
+    fn moist_air_at_sea_level() {
+        // (°C, %, g/kg, kJ/kg, wet bulb °C) read off the ASHRAE chart
+        for (t, rh, w, h, wb) in [(25.0, 50.0, 9.88, 50.32, 17.89),
+                                  (20.0, 100.0, 14.69, 57.41, 20.0),
+                                  (60.0, 20.0, 25.49, 126.95, 34.92),
+                                  (5.0, 80.0, 4.31, 15.86, 3.59)] {
+            close(humidity_ratio_g_per_kg(t, rh, STANDARD_KPA), w, 0.05);
+            close(enthalpy_kj_per_kg(t, rh, STANDARD_KPA), h, 0.1);
+            close(wet_bulb(t, rh, STANDARD_KPA), wb, 0.05);
+        }
+    }
+
+    #[test]
+    fn dry_air() {
+        assert_eq!(humidity_ratio_g_per_kg(25.0, 0.0, STANDARD_KPA), 0.0);
+        close(enthalpy_kj_per_kg(25.0, 0.0, STANDARD_KPA), 25.15, 0.01);
+        close(wet_bulb(25.0, 0.0, STANDARD_KPA), 8.27, 0.05);
+        close(wet_bulb(60.0, 0.0, STANDARD_KPA), 21.25, 0.05);
+    }
+
+    #[test]
+    fn nan_in_nan_out() {
+        assert!(wet_bulb(f32::NAN, 50.0, STANDARD_KPA).is_nan());
+        assert!(wet_bulb(25.0, f32::NAN, STANDARD_KPA).is_nan());
+        let p = psychrometrics(f32::NAN, 50.0, 0.0);
+        assert!(p.dew_point.is_nan() && p.humidity_ratio_g_per_kg.is_nan() && p.wet_bulb.is_nan());
+    }
+
+    #[test]
+    fn standard_pressure_by_default() {
+        let p = psychrometrics(25.0, 50.0, 0.0);
+        assert_eq!(p.humidity_ratio_g_per_kg, humidity_ratio_g_per_kg(25.0, 50.0, STANDARD_KPA));
+        // thinner air holds more water per kg at the same vapor pressure
+        assert!(humidity_ratio_g_per_kg(25.0, 50.0, 85.0) > p.humidity_ratio_g_per_kg);
+    }
+}
@@ -0,0 +1,213 @@
+//! PZEM-004T v3 power meter: Modbus-RTU at 9600 8N1 over a UART. One read of its 10 input
+//! registers gives voltage, current, power, energy, frequency and power factor. The framing
+//! and the crc don't need the esp32, only `esp` moves the bytes.
+//!
+//! As a [ConvertedRead] it reads amps, which the default [crate::Calibration]
+//! passes through unchanged, and [ConvertedRead::power] has the rest of the same read.
+
+use anyhow::anyhow;
+
+use crate::PowerReading;
+
+/// the address every PZEM answers to when it is the only one on the bus
+pub const GENERAL_ADDRESS : u8 = 0xF8;
+
+const READ_INPUT_REGISTERS : u8 = 0x04;
+/// the function code with the high bit set
+const EXCEPTION : u8 = 0x80;
+pub const REGISTERS : u16 = 10;
+
+/// crc-16/modbus: reflected polynomial 0xA001 from 0xFFFF, sent low byte first
+pub fn crc16(data : &[u8]) -> u16 {
+    let mut crc = 0xFFFFu16;
+    for &b in data {
+        crc ^= b as u16;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xA001 } else { crc >> 1 };
+        }
+    }
+    crc
+}
+
+/// `frame` followed by its crc
+pub fn with_crc(mut frame : Vec<u8>) -> Vec<u8> {
+    let crc = crc16(&frame);
+    frame.extend_from_slice(&crc.to_le_bytes());
+    frame
+}
+
+/// request for `count` input registers from `start`
+pub fn read_input_registers(address : u8, start : u16, count : u16) -> Vec<u8> {
+    let [s1, s0] = start.to_be_bytes();
+    let [c1, c0] = count.to_be_bytes();
+    with_crc(vec![address, READ_INPUT_REGISTERS, s1, s0, c1, c0])
+}
+
+/// bytes of the answer to a request for `count` registers
+pub fn response_len(count : u16) -> usize {
+    5 + 2 * count as usize
+}
+
+/// the registers of an answer from `address`, checking its crc, length and exception code
+pub fn parse_registers(address : u8, frame : &[u8]) -> anyhow::Result<Vec<u16>> {
+    if frame.len() < 5 {
+        return Err(anyhow!("modbus: {} bytes is too short", frame.len()));
+    }
+    let (body, crc) = frame.split_at(frame.len() - 2);
+    if crc16(body) != u16::from_le_bytes([crc[0], crc[1]]) {
+        return Err(anyhow!("modbus: crc mismatch in {:02x?}", frame));
+    }
+    if body[0] != address {
+        return Err(anyhow!("modbus: answer from {:#x} instead of {:#x}", body[0], address));
+    }
+    if body[1] == READ_INPUT_REGISTERS | EXCEPTION {
+        return Err(anyhow!("modbus: exception {:#x}", body[2]));
+    }
+    if body[1] != READ_INPUT_REGISTERS {
+        return Err(anyhow!("modbus: unexpected function {:#x}", body[1]));
+    }
+    let n = body[2] as usize;
+    if n % 2 != 0 || body.len() != 3 + n {
+        return Err(anyhow!("modbus: byte count {} doesn't match {} bytes", n, body.len()));
+    }
+    Ok(body[3..].chunks(2).map(|r| u16::from_be_bytes([r[0], r[1]])).collect())
+}
+
+/// the 10 registers from 0, 32 bit values are low word first
+pub fn decode(r : &[u16]) -> anyhow::Result<PowerReading> {
+    if r.len() < REGISTERS as usize {
+        return Err(anyhow!("pzem: {} registers instead of {}", r.len(), REGISTERS));
+    }
+    let long = |k : usize| (r[k] as u32 | (r[k + 1] as u32) << 16) as f32;
+    Ok(PowerReading {
+        volts : r[0] as f32 * 0.1,
+        amps : long(1) * 0.001,
+        watts : long(3) * 0.1,
+        energy_wh : long(5),
+        hz : r[7] as f32 * 0.1,
+        power_factor : r[8] as f32 * 0.01,
+    })
+}
+
+#[cfg(feature = "esp")]
+pub use esp::Pzem;
+
+#[cfg(feature = "esp")]
+mod esp {
+    use std::time::Duration;
+
+    use esp_idf_hal::{delay::TickType, uart::UartDriver};
+
+    use crate::{PowerReading, hal::{ConvertedRead, CurrentSensor}};
+    use super::*;
+
+    /// wait for an answer: 25 bytes take 26 ms at 9600 baud
+    const TIMEOUT_MS : u64 = 200;
+
+    pub struct Pzem<'d> {
+        uart : UartDriver<'d>,
+        address : u8,
+        last : Option<PowerReading>,
+    }
+
+    impl<'d> Pzem<'d> {
+        /// `uart` at 9600 8N1
+        pub fn new(uart : UartDriver<'d>, address : u8) -> Self {
+            Pzem { uart, address, last : None }
+        }
+
+        pub fn read_all(&mut self) -> anyhow::Result<PowerReading> {
+            // drop whatever a timed out answer left behind
+            let mut junk = [0u8; 32];
+            while self.uart.read(&mut junk, 0).unwrap_or(0) > 0 {}
+
+            self.uart.write(&read_input_registers(self.address, 0, REGISTERS))?;
+            let mut frame = vec![0u8; response_len(REGISTERS)];
+            let mut n = 0;
+            let timeout = TickType::from(Duration::from_millis(TIMEOUT_MS)).0;
+            while n < frame.len() {
+                let k = self.uart.read(&mut frame[n..], timeout)?;
+                if k == 0 {
+                    // an exception answer is 5 bytes
+                    break;
+                }
+                n += k;
+            }
+            frame.truncate(n);
+            decode(&parse_registers(self.address, &frame)?)
+        }
+    }
+
+    impl<'d> ConvertedRead for Pzem<'d> {
+        fn read(&mut self) -> anyhow::Result<f32> {
+            let r = self.read_all();
+            self.last = r.as_ref().ok().cloned();
+            Ok(r?.amps)
+        }
+        fn power(&mut self) -> Option<PowerReading> {
+            self.last.clone()
+        }
+    }
+
+    impl<'d> CurrentSensor for Pzem<'d> {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// the answer of a PZEM at 230.1 V, 5.2 A, 1196 W, 131088 Wh, 60 Hz and power factor 0.95
+    const ANSWER : [u8; 25] = [0xF8, 0x04, 0x14, 0x08, 0xFD, 0x14, 0x50, 0x00, 0x00, 0x2E, 0xB8, 0x00, 0x00,
+                               0x00, 0x10, 0x00, 0x02, 0x02, 0x58, 0x00, 0x5F, 0x00, 0x00, 0x80, 0x85];
+
+    #[test]
+    fn crc_of_the_documented_request() {
+        assert_eq!(crc16(&[0xF8, 0x04, 0x00, 0x00, 0x00, 0x0A]), 0x6464);
+        // a frame followed by its crc, low byte first
+        assert_eq!(crc16(&ANSWER[..23]), u16::from_le_bytes([ANSWER[23], ANSWER[24]]));
+    }
+
+    #[test]
+    fn request_bytes() {
+        assert_eq!(read_input_registers(GENERAL_ADDRESS, 0, REGISTERS), [0xF8, 0x04, 0x00, 0x00, 0x00, 0x0A, 0x64, 0x64]);
+        assert_eq!(response_len(REGISTERS), ANSWER.len());
+    }
+
+    #[test]
+    fn parse_an_answer() {
+        let r = parse_registers(GENERAL_ADDRESS, &ANSWER).unwrap();
+        assert_eq!(r, [2301, 0x1450, 0, 0x2EB8, 0, 0x0010, 0x0002, 600, 95, 0]);
+    }
+
+    #[test]
+    fn reject_bad_answers() {
+        // one bit flipped
+        let mut bad = ANSWER;
+        bad[4] ^= 1;
+        assert!(parse_registers(GENERAL_ADDRESS, &bad).unwrap_err().to_string().contains("crc"));
+        // from another meter
+        assert!(parse_registers(0x01, &ANSWER).unwrap_err().to_string().contains("answer from"));
+        // illegal data address
+        let exception = with_crc(vec![0xF8, 0x84, 0x02]);
+        assert_eq!(exception, [0xF8, 0x84, 0x02, 0x12, 0xF0]);
+        assert!(parse_registers(GENERAL_ADDRESS, &exception).unwrap_err().to_string().contains("exception 0x2"));
+        // says 20 bytes but has 18, with a crc that matches
+        let short = with_crc(ANSWER[..21].to_vec());
+        assert!(parse_registers(GENERAL_ADDRESS, &short).unwrap_err().to_string().contains("byte count"));
+        // cut off by the timeout
+        assert!(parse_registers(GENERAL_ADDRESS, &ANSWER[..4]).is_err());
+    }
+
+    #[test]
+    fn decode_low_word_first() {
+        let p = decode(&parse_registers(GENERAL_ADDRESS, &ANSWER).unwrap()).unwrap();
+        let close = |a : f32, b : f32| (a - b).abs() < 1e-3 * b.abs().max(1.0);
+        assert!(close(p.volts, 230.1), "{:?}", p);
+        assert!(close(p.amps, 5.2), "{:?}", p);
+        assert!(close(p.watts, 1196.0), "{:?}", p);
+        // 0x0002 << 16 | 0x0010
+        assert_eq!(p.energy_wh, 131088.0);
+        assert!(close(p.hz, 60.0) && close(p.power_factor, 0.95), "{:?}", p);
+        assert!(decode(&[0; 9]).is_err());
+    }
+}
@@ -0,0 +1,276 @@
+//! Recorded runs: `/measurement.csv` files read back in, and played through the same traits
+//! as the sensors.
+
+use std::{io::BufRead, sync::{Arc, Mutex}};
+
+use anyhow::{anyhow, Context};
+
+use crate::{control, hal::{Climate, ClimatePair, ConvertedRead, CurrentSensor, Scale}};
+
+/// one line of `/measurement.csv`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Row {
+    /// the nvs key of the blob
+    pub j : String,
+    /// index in the blob
+    pub i : usize,
+    /// when the blob was written
+    pub time : i64,
+    pub inside : Climate,
+    pub outside : Climate,
+    pub amps : f32,
+    pub grams : f32,
+}
+
+/// parse the output of [crate::meas::write_csv_rows], skipping the header
+pub fn read_csv(r : impl BufRead) -> anyhow::Result<Vec<Row>> {
+    let mut rows = Vec::new();
+    for (n, line) in r.lines().enumerate() {
+        let line = line?;
+        if (n == 0 && line.starts_with("j,")) || line.trim().is_empty() {
+            continue;
+        }
+        let f : Vec<&str> = line.trim().split(',').collect();
+        // files from before the heater columns have 9
+        if f.len() < 9 {
+            return Err(anyhow!("line {}: expected at least 9 fields, got {}", n + 1, f.len()));
+        }
+        let num = |k : usize| -> anyhow::Result<f32> {
+            f[k].parse().with_context(|| format!("line {} field {}", n + 1, k + 1))
+        };
+        rows.push(Row {
+            j : f[0].to_string(),
+            i : f[1].parse().with_context(|| format!("line {} field 2", n + 1))?,
+            time : f[2].parse().with_context(|| format!("line {} field 3", n + 1))?,
+            inside : Climate { temperature : num(3)?, humidity : num(4)? },
+            outside : Climate { temperature : num(5)?, humidity : num(6)? },
+            amps : num(7)?,
+            grams : num(8)?,
+        });
+    }
+    Ok(rows)
+}
+
+/// consecutive rows of the same blob
+pub fn blobs(rows : &[Row]) -> Vec<&[Row]> {
+    let mut blobs = Vec::new();
+    let mut start = 0;
+    for i in 1..=rows.len() {
+        if i == rows.len() || rows[i].j != rows[start].j {
+            blobs.push(&rows[start..i]);
+            start = i;
+        }
+    }
+    blobs
+}
+
+/// seconds between two blobs that make the second start another run, the device was off
+pub const RUN_GAP_S : i64 = 3600;
+/// A rms below which the dehydrator is off, its fan alone draws more
+pub const OFF_AMPS : f32 = 0.1;
+
+/// The runs of a file that holds several. One ends at a gap of more than [RUN_GAP_S] and
+/// where the dehydrator was switched off, which shows as a whole blob below [OFF_AMPS].
+/// The blobs while it was off are in no run. Without a current sensor (NaN amps) only the
+/// gaps split.
+pub fn runs(rows : &[Row]) -> Vec<&[Row]> {
+    let mut runs = Vec::new();
+    // of the run going, in rows
+    let mut start = None;
+    let mut k = 0;
+    let mut last_time = None;
+    for b in blobs(rows) {
+        let off = b.iter().all(|r| r.amps.is_finite() && r.amps < OFF_AMPS);
+        let gap = last_time.map_or(false, |t| b[0].time - t > RUN_GAP_S);
+        if let Some(s) = start.filter(|_| off || gap) {
+            runs.push(&rows[s..k]);
+            start = None;
+        }
+        if !off && start.is_none() {
+            start = Some(k);
+        }
+        last_time = Some(b[0].time);
+        k += b.len();
+    }
+    if let Some(s) = start {
+        runs.push(&rows[s..]);
+    }
+    runs
+}
+
+/// where a recorded run would have been shut down
+#[derive(Clone, Debug)]
+pub struct Stop {
+    /// the first blob that was [control::dry_enough]
+    pub j : String,
+    /// time of that blob
+    pub time : i64,
+    /// seconds since the first blob
+    pub elapsed : i64,
+    /// on the scale at the end of that blob
+    pub grams : f32,
+}
+
+/// Run the blobs of one run ([runs]) through [control::count_cutoffs] and
+/// [control::dry_enough] with `w_cut` and a trailing mean over `window` measurements. None
+/// if the run was never dry enough.
+pub fn stop(rows : &[Row], w_cut : f32, window : usize) -> Option<Stop> {
+    let t0 = rows.first()?.time;
+    blobs(rows).into_iter().find_map(|b| {
+        let temp : Vec<f32> = b.iter().map(|r| r.inside.temperature).collect();
+        let rh : Vec<f32> = b.iter().map(|r| r.inside.humidity).collect();
+        let cutoffs = control::count_cutoffs(&temp, &rh, w_cut, window);
+        let last = b.last()?;
+        control::dry_enough(cutoffs, b.len())
+            .then(|| Stop { j : last.j.clone(), time : last.time, elapsed : last.time - t0, grams : last.grams })
+    })
+}
+
+struct ReplayState {
+    rows : Vec<Row>,
+    /// the row that the next [ClimatePair::read] returns
+    next : usize,
+}
+
+/// Plays the rows back in order, starting over after the last one. Each
+/// [ClimatePair::read] moves to the next row and [Replay::amps] and [Replay::grams]
+/// read from that same row, the order [crate::control::sample_blob] reads in. The amps
+/// and grams are already calibrated, so the default calibration should be used with them.
+#[derive(Clone)]
+pub struct Replay(Arc<Mutex<ReplayState>>);
+
+impl Replay {
+    pub fn new(rows : Vec<Row>) -> anyhow::Result<Self> {
+        if rows.is_empty() {
+            return Err(anyhow!("nothing to replay"));
+        }
+        Ok(Replay(Arc::new(Mutex::new(ReplayState { rows, next : 0 }))))
+    }
+
+    /// the row read last
+    fn current(&self) -> Row {
+        let st = self.0.lock().unwrap();
+        let i = (st.next + st.rows.len() - 1) % st.rows.len();
+        st.rows[i].clone()
+    }
+
+    pub fn amps(&self) -> ReplayAmps {
+        ReplayAmps(self.clone())
+    }
+
+    pub fn grams(&self) -> ReplayGrams {
+        ReplayGrams(self.clone())
+    }
+}
+
+impl ClimatePair for Replay {
+    fn read(&mut self) -> anyhow::Result<(Climate, Climate)> {
+        let mut st = self.0.lock().unwrap();
+        let row = &st.rows[st.next];
+        let climate = (row.inside, row.outside);
+        st.next = (st.next + 1) % st.rows.len();
+        Ok(climate)
+    }
+}
+
+pub struct ReplayAmps(Replay);
+
+impl ConvertedRead for ReplayAmps {
+    fn read(&mut self) -> anyhow::Result<f32> {
+        Ok(self.0.current().amps)
+    }
+}
+
+impl CurrentSensor for ReplayAmps {}
+
+pub struct ReplayGrams(Replay);
+
+impl ConvertedRead for ReplayGrams {
+    fn read(&mut self) -> anyhow::Result<f32> {
+        Ok(self.0.current().grams)
+    }
+}
+
+impl Scale for ReplayGrams {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meas::{self, Meas, N1};
+
+    /// a blob as `/measurement.csv` has it, inside at 40 °C and `rh` %
+    fn blob(csv : &mut Vec<u8>, j : &str, time : i64, rh : f32, amps : f32) {
+        let mut m = Meas::new();
+        m.time = time;
+        m.inside_temp = [40.0; N1];
+        m.inside_rh = [rh; N1];
+        m.outside_temp = [22.0; N1];
+        m.outside_rh = [50.0; N1];
+        m.amps = [amps; N1];
+        m.grams = [1000.0 + rh; N1];
+        meas::write_csv_rows(csv, j, &meas::decompress(meas::compress(&m))).unwrap();
+    }
+
+    fn header() -> Vec<u8> {
+        let mut csv = Vec::new();
+        meas::write_csv_header(&mut csv).unwrap();
+        csv
+    }
+
+    #[test]
+    fn reads_an_exported_file() {
+        let mut csv = header();
+        blob(&mut csv, "00000001", 1_700_000_000, 80.0, 5.2);
+        blob(&mut csv, "00000002", 1_700_000_200, 5.0, 0.25);
+        let rows = read_csv(&csv[..]).unwrap();
+        assert_eq!(rows.len(), 2 * N1);
+        assert_eq!(rows[0].j, "00000001");
+        assert_eq!(rows[N1 - 1].i, N1 - 1);
+        let r = &rows[N1];
+        assert_eq!((r.j.as_str(), r.i, r.time), ("00000002", 0, 1_700_000_200));
+        assert_eq!(r.inside, Climate { temperature : 40.0, humidity : 5.0 });
+        assert_eq!(r.outside, Climate { temperature : 22.0, humidity : 50.0 });
+        assert!((r.amps - 0.25).abs() < 1e-3 && (r.grams - 1005.0).abs() < 0.1, "{:?}", r);
+        assert_eq!(blobs(&rows).len(), 2);
+
+        // the header is only skipped as the first line, and a short line is an error
+        assert!(read_csv(&b"1,2,3\n"[..]).is_err());
+        assert!(read_csv(&[&b"\n"[..], &header()].concat()[..]).is_err());
+    }
+
+    #[test]
+    fn stops_each_run_of_a_file() {
+        // two runs, each wet and then dry, with the dehydrator off in between and a new
+        // blob every 200 s
+        let mut csv = header();
+        let mut time = 1_700_000_000;
+        for (j, (rh, amps)) in [(80.0, 5.2), (60.0, 5.2), (5.0, 0.25), (5.0, 0.25),
+                                (5.0, 0.0), (5.0, 0.0),
+                                (80.0, 5.2), (40.0, 5.2), (30.0, 0.25), (5.0, 0.25)].iter().enumerate() {
+            blob(&mut csv, &format!("{:08x}", j + 1), time, *rh, *amps);
+            time += 200;
+        }
+        let rows = read_csv(&csv[..]).unwrap();
+        let runs = runs(&rows);
+        assert_eq!(runs.len(), 2);
+        assert_eq!(runs.iter().map(|r| blobs(r).len()).collect::<Vec<_>>(), [4, 4]);
+
+        let stops : Vec<Stop> = runs.iter().map(|r| stop(r, 12.0, 1).unwrap()).collect();
+        assert_eq!((stops[0].j.as_str(), stops[0].elapsed), ("00000003", 400));
+        assert_eq!((stops[1].j.as_str(), stops[1].elapsed), ("0000000a", 600));
+        assert_eq!(stops[1].time, 1_700_000_000 + 9 * 200);
+        // the whole file stops only once, in the first run
+        assert_eq!(stop(&rows, 12.0, 1).unwrap().j, "00000003");
+    }
+
+    #[test]
+    fn a_gap_starts_a_run() {
+        // no current sensor, and the device off for two hours
+        let mut csv = header();
+        blob(&mut csv, "00000001", 1_700_000_000, 80.0, f32::NAN);
+        blob(&mut csv, "00000002", 1_700_000_200, 80.0, f32::NAN);
+        blob(&mut csv, "00000003", 1_700_007_400, 80.0, f32::NAN);
+        let rows = read_csv(&csv[..]).unwrap();
+        assert_eq!(runs(&rows).iter().map(|r| r.len()).collect::<Vec<_>>(), [2 * N1, N1]);
+    }
+}
@@ -0,0 +1,183 @@
+//! The heater current as an RMS value. One ADC sample is a random point on the 50/60 Hz
+//! sine, so a thread of its own samples the sensor in bursts over whole mains cycles at a
+//! fixed rate, subtracts the zero and keeps the RMS and the peak of the last burst for
+//! [RmsCurrent::read].
+//!
+//! The zero is the sensor's output at 0 A, set with [ConvertedRead::zero_offset] when
+//! little or no current flows ([crate::linearly_calibrated::AutoZero]). Until then it is
+//! the mean of each burst, which is only the offset when the burst spans whole cycles, and
+//! the spin timing doesn't promise that.
+//!
+//! The values stay in the sensor's unit (volts for the ACS712), so
+//! [crate::Calibration] maps them to amps as before: tare at
+//! 0 A, then a known load.
+
+use std::{sync::{Arc, Mutex}, thread, time::{Duration, Instant}};
+
+use anyhow::anyhow;
+
+use crate::hal::{ConvertedRead, CurrentSensor};
+
+#[derive(Clone, Copy, Debug)]
+pub struct BurstParams {
+    pub rate_hz : u32,
+    /// 100 ms is 5 cycles at 50 Hz and 6 at 60 Hz
+    pub duration_ms : u32,
+    /// from the start of one burst to the next
+    pub every_ms : u32,
+}
+
+impl Default for BurstParams {
+    fn default() -> Self {
+        BurstParams { rate_hz : 2000, duration_ms : 100, every_ms : 500 }
+    }
+}
+
+/// one burst reduced, in the unit of the sensor
+#[derive(Clone, Copy, Debug)]
+pub struct Burst {
+    pub mean : f32,
+    /// what the rms and peak are taken from
+    pub zero : f32,
+    pub rms : f32,
+    /// largest distance from `zero`
+    pub peak : f32,
+    pub at : Instant,
+}
+
+impl Burst {
+    /// about `zero`, or the mean without one. None unless all `n` samples were read: a burst
+    /// with dropped samples no longer spans whole cycles
+    pub fn new(samples : &[f32], n : usize, zero : Option<f32>, at : Instant) -> Option<Burst> {
+        if samples.is_empty() || samples.len() != n {
+            return None;
+        }
+        let n = samples.len() as f32;
+        let mean = samples.iter().sum::<f32>() / n;
+        let zero = zero.unwrap_or(mean);
+        let rms = (samples.iter().map(|x| (x - zero).powi(2)).sum::<f32>() / n).sqrt();
+        let peak = samples.iter().map(|x| (x - zero).abs()).fold(0.0, f32::max);
+        Some(Burst { mean, zero, rms, peak, at })
+    }
+}
+
+/// The latest burst of the sampling thread. Reading fails when the thread hasn't finished
+/// a burst for 10 periods, so a stuck adc isn't shown as a steady current.
+#[derive(Clone)]
+pub struct RmsCurrent {
+    latest : Arc<Mutex<Option<Burst>>>,
+    max_age : Duration,
+    /// the raw output at 0 A, None until it was zeroed
+    zero : Arc<Mutex<Option<f32>>>,
+}
+
+impl RmsCurrent {
+    /// sample `sensor` on a new thread
+    pub fn spawn(mut sensor : impl ConvertedRead + Send + 'static, params : BurstParams) -> anyhow::Result<Self> {
+        let latest = Arc::new(Mutex::new(None));
+        let latest1 = latest.clone();
+        let zero = Arc::new(Mutex::new(None));
+        let zero1 = zero.clone();
+        let n = (params.rate_hz * params.duration_ms / 1000) as usize;
+        let interval = Duration::from_secs_f64(1.0 / params.rate_hz as f64);
+        thread::Builder::new()
+            .name("rms".into())
+            .stack_size(8 * 1024)
+            .spawn(move || {
+                let mut samples = Vec::with_capacity(n);
+                loop {
+                    let start = Instant::now();
+                    samples.clear();
+                    // spin between samples, a FreeRTOS delay is at least a 10 ms tick
+                    let mut next = start;
+                    for _ in 0..n {
+                        while Instant::now() < next {
+                            std::hint::spin_loop();
+                        }
+                        if let Ok(x) = sensor.read() {
+                            samples.push(x);
+                        }
+                        next += interval;
+                    }
+                    let zero = *zero1.lock().unwrap();
+                    if let Some(b) = Burst::new(&samples, n, zero, start) {
+                        *latest1.lock().unwrap() = Some(b);
+                    }
+                    thread::sleep(Duration::from_millis(params.every_ms as u64).saturating_sub(start.elapsed()));
+                }
+            })?;
+        Ok(RmsCurrent { latest, max_age : Duration::from_millis(10 * params.every_ms as u64), zero })
+    }
+
+    pub fn burst(&self) -> anyhow::Result<Burst> {
+        match *self.latest.lock().unwrap() {
+            Some(b) if b.at.elapsed() <= self.max_age => Ok(b),
+            Some(b) => Err(anyhow!("no current burst for {:?}", b.at.elapsed())),
+            None => Err(anyhow!("no current burst yet")),
+        }
+    }
+}
+
+impl ConvertedRead for RmsCurrent {
+    fn read(&mut self) -> anyhow::Result<f32> {
+        Ok(self.burst()?.rms)
+    }
+    fn peak(&mut self) -> Option<f32> {
+        self.burst().ok().map(|b| b.peak)
+    }
+    /// the mean of the last burst, which is the offset when no current flows
+    fn zero_offset(&mut self) -> Option<anyhow::Result<f32>> {
+        Some(self.burst().map(|b| {
+            let mut zero = self.zero.lock().unwrap();
+            let moved = b.mean - zero.unwrap_or(b.mean);
+            *zero = Some(b.mean);
+            moved
+        }))
+    }
+}
+
+impl CurrentSensor for RmsCurrent {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a default burst of `amplitude` at `hz` on `offset`
+    fn sine(amplitude : f32, hz : f32, offset : f32) -> Vec<f32> {
+        let p = BurstParams::default();
+        let n = (p.rate_hz * p.duration_ms / 1000) as usize;
+        (0..n).map(|k| offset + amplitude * (2.0 * std::f32::consts::PI * hz * k as f32 / p.rate_hz as f32).sin()).collect()
+    }
+
+    #[test]
+    fn rms_of_a_sine() {
+        for hz in [50.0, 60.0] {
+            let s = sine(2.0, hz, 1.65);
+            let b = Burst::new(&s, s.len(), None, Instant::now()).unwrap();
+            assert!((b.rms - 2.0 / 2f32.sqrt()).abs() < 1e-3, "{} Hz: {:?}", hz, b);
+            assert!((b.zero - 1.65).abs() < 1e-3 && b.mean == b.zero, "{} Hz: {:?}", hz, b);
+            assert!((b.peak - 2.0).abs() < 0.02, "{} Hz: {:?}", hz, b);
+        }
+    }
+
+    #[test]
+    fn offset_is_removed() {
+        let s = vec![1.65; 200];
+        let b = Burst::new(&s, 200, None, Instant::now()).unwrap();
+        assert!(b.rms.abs() < 1e-6 && b.peak.abs() < 1e-6, "{:?}", b);
+
+        // about a zero set at 0 A, the offset left over shows up
+        let s = sine(2.0, 60.0, 1.65);
+        let b = Burst::new(&s, s.len(), Some(1.6), Instant::now()).unwrap();
+        assert!((b.rms - (2.0 + 0.05f32.powi(2)).sqrt()).abs() < 1e-3, "{:?}", b);
+        assert!((b.mean - 1.65).abs() < 1e-3 && b.zero == 1.6, "{:?}", b);
+    }
+
+    #[test]
+    fn short_bursts_are_dropped() {
+        let s = sine(2.0, 60.0, 1.65);
+        assert!(Burst::new(&s[..150], s.len(), None, Instant::now()).is_none());
+        assert!(Burst::new(&[], 0, None, Instant::now()).is_none());
+        assert!(Burst::new(&[], s.len(), Some(1.65), Instant::now()).is_none());
+    }
+}
@@ -0,0 +1,205 @@
+use std::sync::Arc;
+
+use serde::{Serialize, de::DeserializeOwned};
+use log::*;
+
+use crate::{auth::{Auth, Denied}, routes::Route, assets::Asset, ValidationErrors};
+
+/// requests with a longer json body are rejected
+pub const MAX_BODY : usize = 4096;
+
+/// the only request headers any handler looks at, so the server only has to copy these
+pub const HEADERS : &[&str] = &["Authorization", "Cookie", "If-None-Match"];
+
+/// what the http server (esp_http.rs or the host one) passes to a handler
+pub struct HttpRequest {
+    /// those of [HEADERS] that were sent
+    pub headers : Vec<(&'static str, String)>,
+    /// at most [MAX_BODY] + 1 bytes, so that longer bodies can be rejected
+    pub body : Vec<u8>,
+}
+
+impl HttpRequest {
+    pub fn header(&self, name : &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+pub enum Body {
+    Empty,
+    Bytes(Vec<u8>),
+    Static(&'static [u8]),
+    /// written piece by piece, like the csv export that doesn't fit in ram
+    Stream(Box<dyn FnOnce(&mut dyn std::io::Write) -> anyhow::Result<()> + Send>),
+}
+
+pub struct HttpResponse {
+    pub status : u16,
+    pub headers : Vec<(&'static str, String)>,
+    pub body : Body,
+}
+
+impl HttpResponse {
+    pub fn new(status : u16) -> Self {
+        HttpResponse { status, headers : Vec::new(), body : Body::Empty }
+    }
+
+    pub fn header(mut self, name : &'static str, value : impl Into<String>) -> Self {
+        self.headers.push((name, value.into()));
+        self
+    }
+
+    pub fn body(mut self, body : Body) -> Self {
+        self.body = body;
+        self
+    }
+
+    /// 200 with `value` as json
+    pub fn json(value : &impl Serialize) -> Self {
+        match serde_json::to_vec(value) {
+            Ok(v) => HttpResponse::new(200)
+                .header("Content-Type", "application/json")
+                .body(Body::Bytes(v)),
+            Err(e) => ApiError::Internal(e.into()).into_response(),
+        }
+    }
+}
+
+/// everything a typed handler can fail with, and the status code it turns into
+pub enum ApiError {
+    /// 400 with the [ValidationErrors] as json
+    BadRequest(ValidationErrors),
+    /// 401 or 429
+    Denied(Denied),
+    /// 500 with the message as text
+    Internal(anyhow::Error),
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(e : anyhow::Error) -> Self { ApiError::Internal(e) }
+}
+
+impl From<Denied> for ApiError {
+    fn from(e : Denied) -> Self { ApiError::Denied(e) }
+}
+
+impl From<ValidationErrors> for ApiError {
+    fn from(e : ValidationErrors) -> Self { ApiError::BadRequest(e) }
+}
+
+impl ApiError {
+    pub fn into_response(self) -> HttpResponse {
+        match self {
+            ApiError::BadRequest(errors) => {
+                let mut rsp = HttpResponse::json(&errors);
+                rsp.status = 400;
+                rsp
+            },
+            ApiError::Denied(d) => d.response(),
+            ApiError::Internal(e) => {
+                warn!("{:?}", e);
+                HttpResponse::new(500)
+                    .header("Content-Type", "text/plain")
+                    .body(Body::Bytes(format!("{:#}", e).into_bytes()))
+            },
+        }
+    }
+}
+
+/// decode the json body. An empty body is read as `null` so that `()` handlers don't need one
+pub fn read_json<Req : DeserializeOwned>(rq : &HttpRequest) -> Result<Req, ApiError> {
+    if rq.body.len() > MAX_BODY {
+        return Err(ApiError::BadRequest(ValidationErrors::body(format!("longer than {} bytes", MAX_BODY))));
+    }
+    let body : &[u8] = if rq.body.is_empty() { b"null" } else { &rq.body };
+    serde_json::from_slice(body).map_err(|e| ApiError::BadRequest(ValidationErrors::body(e)))
+}
+
+pub type HandlerFn = Box<dyn Fn(&HttpRequest) -> HttpResponse + Send + Sync>;
+
+/// one registered uri and method
+pub struct Handler {
+    pub uri : String,
+    pub method : &'static str,
+    pub handle : HandlerFn,
+}
+
+/// Collects the handlers for [crate::routes::ROUTES]. The password check, json decoding
+/// of the request, encoding of the response and the status codes are done here instead
+/// of in every handler. esp_http.rs then registers them with the ESP-IDF server.
+pub struct Router {
+    auth : Arc<Auth>,
+    handlers : Vec<Handler>,
+}
+
+impl Router {
+    pub fn new(auth : Arc<Auth>) -> Self {
+        Router { auth, handlers : Vec::new() }
+    }
+
+    /// `f` gets the decoded body and its result is sent back as json
+    pub fn json<Req, Rsp, F>(&mut self, route : &'static Route, f : F) -> &mut Self
+    where Req : DeserializeOwned,
+          Rsp : Serialize,
+          F : Fn(Req) -> Result<Rsp, ApiError> + Send + Sync + 'static {
+        self.raw(route, move |rq| {
+            let req = read_json::<Req>(rq)?;
+            Ok(HttpResponse::json(&f(req)?))
+        })
+    }
+
+    /// for responses that are not json (csv, cookies): only the password is checked here
+    pub fn raw<F>(&mut self, route : &'static Route, f : F) -> &mut Self
+    where F : Fn(&HttpRequest) -> Result<HttpResponse, ApiError> + Send + Sync + 'static {
+        let auth = self.auth.clone();
+        self.handlers.push(Handler {
+            uri : route.uri(),
+            method : route.method,
+            handle : Box::new(move |rq| {
+                auth.check(rq, route.access)
+                    .map_err(ApiError::from)
+                    .and_then(|()| f(rq))
+                    .unwrap_or_else(ApiError::into_response)
+            }),
+        });
+        self
+    }
+
+    /// Serve the web interface. Every browser accepts gzip so there is no uncompressed
+    /// copy. A matching `If-None-Match` gets a 304 without the body.
+    pub fn assets(&mut self, assets : &'static [Asset]) -> &mut Self {
+        for asset in assets {
+            self.handlers.push(Handler {
+                uri : asset.path.to_string(),
+                method : "GET",
+                handle : Box::new(move |rq| {
+                    let mut rsp = HttpResponse::new(200)
+                        .header("ETag", asset.etag)
+                        .header("Cache-Control", asset.cache_control());
+                    if rq.header("If-None-Match") == Some(asset.etag) {
+                        rsp.status = 304;
+                        return rsp;
+                    }
+                    rsp.header("Content-Type", asset.content_type)
+                        .header("Content-Encoding", "gzip")
+                        .body(Body::Static(asset.gz))
+                }),
+            });
+        }
+        self
+    }
+
+    /// the handler for `method` and `uri`, ignoring the query string
+    pub fn find(&self, method : &str, uri : &str) -> Option<&HandlerFn> {
+        let path = uri.split('?').next().unwrap_or(uri);
+        self.handlers.iter()
+            .find(|h| h.method == method && h.uri == path)
+            .map(|h| &h.handle)
+    }
+
+    pub fn into_handlers(self) -> Vec<Handler> {
+        self.handlers
+    }
+}
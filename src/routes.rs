@@ -0,0 +1,83 @@
+//! The http api. Like src/json.rs this file is also compiled into /build.rs, which writes
+//! [ROUTES] to www/routes.json next to json.ts. The handlers are registered in api.rs
+//! with `router.json(&routes::CONFIG_GET, ...)` so the path, method and password check
+//! are only written here.
+use serde::Serialize;
+
+pub const API_PREFIX : &str = "/api/v1";
+
+/// who may call a route, see auth.rs
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Access {
+    /// no password needed (login, static files)
+    Open,
+    /// open when the password was set with `open_reads`
+    Read,
+    /// always needs the password once one is provisioned
+    Write,
+}
+
+#[derive(Serialize)]
+pub struct Route {
+    pub method : &'static str,
+    /// relative to [API_PREFIX]
+    pub path : &'static str,
+    /// name in json.ts of the request body, "null" when there is none
+    pub request : &'static str,
+    /// name in json.ts of the response body, or its content type if it isn't json
+    pub response : &'static str,
+    pub access : Access,
+}
+
+impl Route {
+    pub fn uri(&self) -> String {
+        format!("{}{}", API_PREFIX, self.path)
+    }
+}
+
+pub const LOGIN : Route = Route { method : "POST", path : "/login", request : "LoginRequest", response : "Set-Cookie", access : Access::Open };
+pub const PASSWORD : Route = Route { method : "POST", path : "/password", request : "PasswordRequest", response : "null", access : Access::Write };
+pub const CALIB_GET : Route = Route { method : "GET", path : "/calib", request : "null", response : "CalibrationReport[]", access : Access::Read };
+pub const CALIB_POST : Route = Route { method : "POST", path : "/calib", request : "CalibrationRequest", response : "null", access : Access::Write };
+pub const CALIB_HISTORY : Route = Route { method : "GET", path : "/calib/history", request : "null", response : "CalibrationHistory[]", access : Access::Read };
+pub const CALIB_ROLLBACK : Route = Route { method : "POST", path : "/calib/rollback", request : "RollbackRequest", response : "CalibrationReport", access : Access::Write };
+pub const ZERO : Route = Route { method : "POST", path : "/zero", request : "ZeroRequest", response : "ZeroShift", access : Access::Write };
+pub const CONFIG_GET : Route = Route { method : "GET", path : "/config", request : "null", response : "Config", access : Access::Read };
+pub const CONFIG_POST : Route = Route { method : "POST", path : "/config", request : "Config", response : "null", access : Access::Write };
+pub const SHUTDOWN : Route = Route { method : "POST", path : "/shutdown", request : "null", response : "null", access : Access::Write };
+pub const RESTART : Route = Route { method : "POST", path : "/restart", request : "null", response : "null", access : Access::Write };
+pub const STEPPER_GET : Route = Route { method : "GET", path : "/stepper", request : "null", response : "StepperStatus", access : Access::Read };
+pub const STEPPER_JOG : Route = Route { method : "POST", path : "/stepper/jog", request : "JogRequest", response : "StepperMove", access : Access::Write };
+pub const STEPPER_FRACTION : Route = Route { method : "POST", path : "/stepper/fraction", request : "FractionRequest", response : "StepperMove", access : Access::Write };
+pub const STEPPER_CALIBRATE : Route = Route { method : "POST", path : "/stepper/calibrate", request : "StepperCalibrateRequest", response : "StepperMove", access : Access::Write };
+pub const STEPPER_RESUME : Route = Route { method : "POST", path : "/stepper/resume", request : "null", response : "StepperStatus", access : Access::Write };
+pub const HARDWARE_GET : Route = Route { method : "GET", path : "/hardware", request : "null", response : "Hardware", access : Access::Read };
+pub const HARDWARE_POST : Route = Route { method : "POST", path : "/hardware", request : "Hardware", response : "null", access : Access::Write };
+pub const STATUS : Route = Route { method : "GET", path : "/status", request : "null", response : "Status", access : Access::Read };
+pub const MEASUREMENT_CSV : Route = Route { method : "GET", path : "/measurement.csv", request : "null", response : "text/csv", access : Access::Read };
+pub const SENSORS_CSV : Route = Route { method : "GET", path : "/sensors.csv", request : "null", response : "text/csv", access : Access::Read };
+
+pub const ROUTES : &[Route] = &[
+    LOGIN,
+    PASSWORD,
+    CALIB_GET,
+    CALIB_POST,
+    CALIB_HISTORY,
+    CALIB_ROLLBACK,
+    ZERO,
+    CONFIG_GET,
+    CONFIG_POST,
+    SHUTDOWN,
+    RESTART,
+    STEPPER_GET,
+    STEPPER_JOG,
+    STEPPER_FRACTION,
+    STEPPER_CALIBRATE,
+    STEPPER_RESUME,
+    HARDWARE_GET,
+    HARDWARE_POST,
+    STATUS,
+    MEASUREMENT_CSV,
+    SENSORS_CSV,
+];
@@ -0,0 +1,67 @@
+//! SCT-013 clamp on the mains lead, instead of the ACS712 inside the dehydrator. The
+//! secondary current runs through a burden resistor sitting on a bias of half the supply,
+//! so the adc sees a sine around 1.65 V. [crate::rms] removes the bias and takes the RMS;
+//! this only scales the millivolts of [crate::adc] to primary amps by the turns ratio and the burden. The
+//! [crate::Calibration] on top starts out as the identity and
+//! corrects what the nominal values get wrong.
+
+use crate::Attenuation;
+
+/// nominal conversion of an SCT-013 on the adc
+#[derive(Clone, Copy, Debug)]
+pub struct SctParams {
+    /// 2000 for the current output SCT-013-000 (100 A : 50 mA)
+    pub turns : f32,
+    pub burden_ohms : f32,
+    /// top of the adc range at the channel's attenuation
+    pub full_scale_mv : f32,
+}
+
+impl SctParams {
+    pub fn new(turns : f32, burden_ohms : f32, atten : Attenuation) -> Self {
+        SctParams { turns, burden_ohms, full_scale_mv : atten.full_scale_mv() }
+    }
+
+    /// primary amps per mV across the burden
+    pub fn amps_per_mv(&self) -> f32 {
+        1.0 / 1000.0 / self.burden_ohms * self.turns
+    }
+
+    /// the largest primary current before the burden voltage leaves the adc range around the
+    /// bias, as an rms value
+    pub fn max_rms_amps(&self) -> f32 {
+        self.full_scale_mv / 2.0 / 1000.0 / self.burden_ohms * self.turns / std::f32::consts::SQRT_2
+    }
+}
+
+#[cfg(feature = "esp")]
+pub use esp::Sct013;
+
+#[cfg(feature = "esp")]
+mod esp {
+    use esp_idf_hal::{gpio::ADCPin, peripheral::Peripheral};
+
+    use crate::{Attenuation, adc, hal::ConvertedRead};
+    use super::SctParams;
+
+    /// instantaneous primary amps plus the bias, for [crate::rms::RmsCurrent::spawn]
+    pub struct Sct013<'d> {
+        mv : Box<dyn ConvertedRead + Send + 'd>,
+        amps_per_mv : f32,
+    }
+
+    impl<'d> Sct013<'d> {
+        pub fn new<PIN : ADCPin>(pin : impl Peripheral<P = PIN> + 'd,
+                                 adc : impl Peripheral<P = PIN::Adc> + 'd,
+                                 atten : Attenuation,
+                                 params : SctParams) -> anyhow::Result<Self> {
+            Ok(Sct013 { mv : adc::millivolts(pin, adc, atten)?, amps_per_mv : params.amps_per_mv() })
+        }
+    }
+
+    impl<'d> ConvertedRead for Sct013<'d> {
+        fn read(&mut self) -> anyhow::Result<f32> {
+            Ok(self.mv.read()? * self.amps_per_mv)
+        }
+    }
+}
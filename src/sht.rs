@@ -0,0 +1,375 @@
+//! SHT31 reads that survive a flaky bus. A failed read is retried with a growing delay;
+//! after [RESET_AFTER] failures in a row the sensor gets a soft reset and periodic mode is
+//! started again. A sample that still fails is NaN and counted in [Sht::errors], so the
+//! measurement loop keeps going. A sensor that is missing at boot is NaN until it answers.
+//!
+//! The on-chip heater dries a sensor that has been sitting near 100 % RH, where it drifts
+//! ([HeaterPolicy]). The `sht31` crate has no heater or status register commands, so they
+//! are sent here on a second proxy of the same bus, with periodic mode stopped meanwhile.
+//!
+//! Any number of sensors make up a [ShtArray], each with a [Placement]: its name, its [Role],
+//! its address and the TCA9548A channel in front of it, if any. The sensors of an array share
+//! the bus type but can be on different buses. The tray sensors have the addresses of the ones
+//! on the bus itself, so every access opens only the sensor's own channel and closes all the
+//! others.
+
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+
+use embedded_hal::blocking::i2c::{WriteRead, Write};
+use log::*;
+use shared_bus::{I2cProxy, BusMutex, BusManager};
+use sht31::{SHT31, prelude::{Periodic, MPS, Sht31Measure, Sht31Reader}, DeviceAddr};
+
+use crate::{Role, TraySensor, hal::{Channel, Climate, ClimatePair}};
+
+/// tries per sample
+const RETRIES : u32 = 3;
+/// first delay between tries, doubled after each
+const BACKOFF_MS : u64 = 10;
+/// consecutive failed samples before a soft reset
+const RESET_AFTER : u32 = 3;
+/// samples between attempts to bring back a sensor that is missing
+const REPROBE_EVERY : u32 = 30;
+
+/// soft reset command, datasheet table 13
+const SOFT_RESET : [u8; 2] = [0x30, 0xA2];
+/// stops periodic mode so that other commands are accepted, table 12
+const BREAK : [u8; 2] = [0x30, 0x93];
+/// table 15
+const HEATER_ON : [u8; 2] = [0x30, 0x6D];
+const HEATER_OFF : [u8; 2] = [0x30, 0x66];
+/// tables 16 and 18
+const READ_STATUS : [u8; 2] = [0xF3, 0x2D];
+const CLEAR_STATUS : [u8; 2] = [0x30, 0x41];
+
+/// When to run the heater. It warms the sensor by a few °C, so samples taken while it is
+/// on and for `cool_for` afterwards are marked (see [ClimatePair::heating]).
+#[derive(Clone, Copy, Debug)]
+pub struct HeaterPolicy {
+    /// heat after the humidity has been at least `high_rh` for `high_for`
+    pub high_rh : f32,
+    pub high_for : Duration,
+    /// also heat when it has been this long since the last time, None for only the humidity rule
+    pub every : Option<Duration>,
+    pub on_for : Duration,
+    pub cool_for : Duration,
+}
+
+impl Default for HeaterPolicy {
+    fn default() -> Self {
+        HeaterPolicy {
+            high_rh : 95.0,
+            high_for : Duration::from_secs(10 * 60),
+            every : None,
+            on_for : Duration::from_secs(30),
+            cool_for : Duration::from_secs(60),
+        }
+    }
+}
+
+/// crc-8 of the sensor, polynomial 0x31 starting from 0xff (datasheet 4.12)
+fn crc8(data : &[u8]) -> u8 {
+    let mut crc = 0xffu8;
+    for &b in data {
+        crc ^= b;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x31 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+pub const MISSING : Climate = Climate { temperature : f32::NAN, humidity : f32::NAN };
+
+/// where one sensor is and what it measures
+#[derive(Clone, Copy, Debug)]
+pub struct Placement {
+    pub name : &'static str,
+    pub role : Role,
+    /// 0x44 with ADDR low, 0x45 with ADDR high
+    pub address : u8,
+    /// (address of a TCA9548A, channel 0 to 7) between the bus and the sensor
+    pub mux : Option<(u8, u8)>,
+}
+
+impl TraySensor {
+    /// the name is leaked, which is fine for the sensors set up once at boot
+    pub fn placement(&self) -> Placement {
+        Placement {
+            name : Box::leak(format!("tray {}", self.tray).into_boxed_str()),
+            role : Role::Tray(self.tray),
+            address : self.address,
+            mux : self.mux.map(|[a, c]| (a, c)),
+        }
+    }
+}
+
+pub struct Sht<'a, T : BusMutex> {
+    sht : SHT31<Periodic, I2cProxy<'a, T>>,
+    /// for the commands the driver doesn't have, and the multiplexer
+    raw : I2cProxy<'a, T>,
+    address : u8,
+    pub name : &'static str,
+    pub role : Role,
+    mux : Option<(u8, u8)>,
+    /// addresses of every TCA9548A on the bus
+    muxes : Vec<u8>,
+    /// whether periodic mode is running
+    present : bool,
+    failures_in_a_row : u32,
+    /// samples that came out NaN since boot
+    pub errors : u32,
+    pub heater : HeaterPolicy,
+    /// when the heater was switched on, None when it is off
+    heater_since : Option<Instant>,
+    /// when the heater was last switched off
+    heated_at : Option<Instant>,
+    /// since when the humidity has been at least `heater.high_rh`
+    high_since : Option<Instant>,
+    /// whether the last sample was taken with the heater on or cooling down
+    heating : bool,
+}
+
+impl<'a, T : BusMutex> Sht<'a, T> where <T as BusMutex>::Bus : WriteRead + Write {
+    /// starts periodic mode; a sensor that doesn't answer is kept and retried later. `muxes`
+    /// are the addresses of all the multiplexers on the bus
+    pub fn new(bus : &'a BusManager<T>, p : Placement, muxes : &[u8]) -> Self {
+        let address = if p.address == 0x45 { DeviceAddr::AD1 } else { DeviceAddr::AD0 };
+        let mut sht = SHT31::new(bus.acquire_i2c())
+            .with_mode(Periodic::new().with_mps(MPS::Normal))
+            .with_address(address);
+        sht.set_unit(sht31::TemperatureUnit::Celsius);
+        let mut s = Sht { sht, raw : bus.acquire_i2c(), address : p.address, name : p.name, role : p.role, mux : p.mux, muxes : muxes.to_vec(),
+                          present : false, failures_in_a_row : 0, errors : 0,
+                          heater : HeaterPolicy::default(), heater_since : None, heated_at : None, high_since : None, heating : false };
+        s.start();
+        if !s.present {
+            warn!("{} (sht31 at {:#x}, mux {:?}) is missing", p.name, p.address, p.mux);
+        }
+        s
+    }
+
+    /// route the bus to this sensor's multiplexer channel and close all the others, which
+    /// the other sensors may have opened. False when the sensor's own multiplexer doesn't ack
+    fn select(&mut self) -> bool {
+        let mut ok = true;
+        for &mux in &self.muxes {
+            let open = match self.mux {
+                Some((m, channel)) if m == mux => 1 << channel,
+                _ => 0,
+            };
+            // a multiplexer that doesn't ack closing is missing, and has nothing open
+            if self.raw.write(mux, &[open]).is_err() && open != 0 {
+                ok = false;
+            }
+        }
+        ok
+    }
+
+    /// soft reset and start periodic mode again. The reset also turns the heater off
+    fn start(&mut self) {
+        if !self.select() {
+            self.present = false;
+            return;
+        }
+        if self.raw.write(self.address, &SOFT_RESET).is_ok() {
+            // 1.5 ms until the sensor listens again
+            std::thread::sleep(Duration::from_millis(2));
+        }
+        if self.heater_since.take().is_some() {
+            self.heated_at = Some(Instant::now());
+        }
+        self.present = self.sht.measure().is_ok();
+    }
+
+    /// `f` between stopping and restarting periodic mode
+    fn stopped<A>(&mut self, f : impl FnOnce(&mut Self) -> anyhow::Result<A>) -> anyhow::Result<A> {
+        if !self.select() {
+            return Err(anyhow!("{}: no ack from the multiplexer", self.name));
+        }
+        self.raw.write(self.address, &BREAK).map_err(|_| anyhow!("{}: no ack for break", self.name))?;
+        std::thread::sleep(Duration::from_millis(1));
+        let r = f(self);
+        if self.sht.measure().is_err() {
+            self.present = false;
+        }
+        r
+    }
+
+    fn command(&mut self, cmd : [u8; 2]) -> anyhow::Result<()> {
+        self.stopped(|s| s.raw.write(s.address, &cmd).map_err(|_| anyhow!("{}: no ack for {:02x?}", s.name, cmd)))
+    }
+
+    /// the status register, cleared afterwards. None when the sensor is missing
+    pub fn status(&mut self) -> anyhow::Result<Option<u16>> {
+        if !self.present {
+            return Ok(None);
+        }
+        self.stopped(|s| {
+            let mut buf = [0u8; 3];
+            s.raw.write_read(s.address, &READ_STATUS, &mut buf).map_err(|_| anyhow!("{}: no ack for status", s.name))?;
+            if crc8(&buf[..2]) != buf[2] {
+                return Err(anyhow!("{}: status checksum", s.name));
+            }
+            s.raw.write(s.address, &CLEAR_STATUS).map_err(|_| anyhow!("{}: no ack for clear status", s.name))?;
+            Ok(Some(u16::from_be_bytes([buf[0], buf[1]])))
+        })
+    }
+
+    /// switch the heater on or off following [HeaterPolicy] after a sample of `humidity`
+    fn follow_heater(&mut self, humidity : f32) {
+        let now = Instant::now();
+        let p = self.heater;
+        match self.heater_since {
+            Some(since) if now - since >= p.on_for => match self.command(HEATER_OFF) {
+                Ok(()) => {
+                    info!("{}: heater off", self.name);
+                    self.heater_since = None;
+                    self.heated_at = Some(now);
+                    self.high_since = None;
+                },
+                Err(e) => warn!("{:?}", e),
+            },
+            Some(_) => (),
+            None => {
+                let cooling = self.heated_at.map_or(false, |t| now - t < p.cool_for);
+                if cooling {
+                    return;
+                }
+                self.high_since = if humidity >= p.high_rh { self.high_since.or(Some(now)) } else { None };
+                let saturated = self.high_since.map_or(false, |t| now - t >= p.high_for);
+                let scheduled = p.every.map_or(false, |e| self.heated_at.map_or(true, |t| now - t >= e));
+                if saturated || scheduled {
+                    match self.command(HEATER_ON) {
+                        Ok(()) => {
+                            info!("{}: heater on ({})", self.name, if saturated { "saturated" } else { "scheduled" });
+                            self.heater_since = Some(now);
+                        },
+                        Err(e) => warn!("{:?}", e),
+                    }
+                }
+            },
+        }
+    }
+
+    /// NaN if the sensor didn't answer after retrying
+    pub fn read(&mut self) -> Climate {
+        self.heating = false;
+        if !self.present {
+            self.errors += 1;
+            if self.errors % REPROBE_EVERY == 0 {
+                self.start();
+            }
+            return MISSING;
+        }
+        let mut delay = BACKOFF_MS;
+        for attempt in 0..RETRIES {
+            let r = if self.select() { self.sht.read().map_err(|e| anyhow!("{:?}", e)) } else { Err(anyhow!("no ack from the multiplexer")) };
+            match r {
+                Ok(r) => {
+                    self.failures_in_a_row = 0;
+                    self.heating = self.heater_since.is_some() ||
+                        self.heated_at.map_or(false, |t| t.elapsed() < self.heater.cool_for);
+                    self.follow_heater(r.humidity);
+                    return Climate { temperature : r.temperature, humidity : r.humidity };
+                },
+                Err(e) => {
+                    debug!("{} attempt {}: {:?}", self.name, attempt, e);
+                    std::thread::sleep(Duration::from_millis(delay));
+                    delay *= 2;
+                },
+            }
+        }
+        self.errors += 1;
+        self.failures_in_a_row += 1;
+        if self.failures_in_a_row >= RESET_AFTER {
+            warn!("{}: {} failed samples, resetting", self.name, self.failures_in_a_row);
+            self.failures_in_a_row = 0;
+            self.start();
+        }
+        MISSING
+    }
+}
+
+/// The sensors of the dehydrator. As a [ClimatePair] inside is the exhaust sensor, or the
+/// mean of the trays when there is none or it didn't answer, and outside is the ambient one.
+pub struct ShtArray<'a, T : BusMutex> {
+    pub sensors : Vec<Sht<'a, T>>,
+    /// of the last read, in the order of `sensors`
+    last : Vec<Climate>,
+}
+
+impl<'a, T : BusMutex> ShtArray<'a, T> where <T as BusMutex>::Bus : WriteRead + Write {
+    pub fn new(sensors : Vec<Sht<'a, T>>) -> Self {
+        let last = vec![MISSING; sensors.len()];
+        ShtArray { sensors, last }
+    }
+
+    /// NaN samples of all sensors
+    pub fn errors(&self) -> u32 {
+        self.sensors.iter().map(|s| s.errors).sum()
+    }
+
+    fn answered(&self, k : usize) -> bool {
+        !self.last[k].temperature.is_nan() && !self.last[k].humidity.is_nan()
+    }
+
+    /// indices of the sensors that make up (inside, outside)
+    fn pair(&self) -> (Vec<usize>, Vec<usize>) {
+        let of = |want : &dyn Fn(Role) -> bool| -> Vec<usize> {
+            (0..self.sensors.len()).filter(|&k| want(self.sensors[k].role) && self.answered(k)).collect()
+        };
+        let exhaust = of(&|r| r == Role::Exhaust);
+        let inside = if exhaust.is_empty() { of(&|r| matches!(r, Role::Tray(_))) } else { exhaust };
+        let outside = of(&|r| r == Role::Ambient).into_iter().take(1).collect();
+        (inside, outside)
+    }
+
+    fn mean(&self, ks : &[usize]) -> Climate {
+        if ks.is_empty() {
+            return MISSING;
+        }
+        let n = ks.len() as f32;
+        Climate {
+            temperature : ks.iter().map(|&k| self.last[k].temperature).sum::<f32>() / n,
+            humidity : ks.iter().map(|&k| self.last[k].humidity).sum::<f32>() / n,
+        }
+    }
+}
+
+/// never fails, a sensor that doesn't answer is NaN
+impl<'a, T : BusMutex> ClimatePair for ShtArray<'a, T> where <T as BusMutex>::Bus : WriteRead + Write {
+    fn read(&mut self) -> anyhow::Result<(Climate, Climate)> {
+        for k in 0..self.sensors.len() {
+            self.last[k] = self.sensors[k].read();
+        }
+        let (inside, outside) = self.pair();
+        Ok((self.mean(&inside), self.mean(&outside)))
+    }
+    fn heating(&self) -> [bool; 2] {
+        let (inside, outside) = self.pair();
+        [inside.iter().any(|&k| self.sensors[k].heating), outside.iter().any(|&k| self.sensors[k].heating)]
+    }
+    /// the registers of the first sensor of inside and outside. A sensor whose register can't
+    /// be read is None, the other one is still reported
+    fn status(&mut self) -> anyhow::Result<[Option<u16>; 2]> {
+        let (inside, outside) = self.pair();
+        let mut status = [None, None];
+        for (s, k) in status.iter_mut().zip([inside.first(), outside.first()]) {
+            if let Some(&k) = k {
+                match self.sensors[k].status() {
+                    Ok(x) => *s = x,
+                    Err(e) => warn!("{:?}", e),
+                }
+            }
+        }
+        Ok(status)
+    }
+    fn channels(&self) -> Vec<Channel> {
+        self.sensors.iter().zip(&self.last)
+            .map(|(s, &climate)| Channel { name : s.name.to_string(), role : s.role, climate, heating : s.heating })
+            .collect()
+    }
+}
@@ -0,0 +1,329 @@
+//! A dehydrator made of a few differential equations, for running the control logic on the
+//! host faster than real time.
+//!
+//! The heater is switched by the bimetallic thermostat, which turns it on below
+//! `setpoint - hysteresis/2` and off above `setpoint + hysteresis/2`. The setpoint follows
+//! the dial. The chamber is one thermal mass losing heat to the room, and the fan pulls room
+//! air through it. The food's free water evaporates in proportion to how much is left and how
+//! far the chamber air is from saturation, which cools the chamber, raises the inside
+//! humidity and lightens the scale. The readings are the raw values the drivers would
+//! return, so the calibrations in linearly_calibrated.rs still apply.
+//!
+//! ```ignore
+//! let sim = Sim::new(PlantParams::default(), Pace::Stepped);
+//! let sensors = Mutex::new([CalibratedSensor::new(sim.current(), store.clone(), "ACS712".into()),
+//!                           CalibratedSensor::new(sim.scale(), store, "HX711".into())]);
+//! control::sample_blob(&mut meas, &mut sim.clone(), &sensors, &mut Weigher::new(Default::default()), &mut Vec::new(), &config, &status, &sim)?;
+//! ```
+
+use std::{sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+use crate::{control::abs_humidity_g_per_m3, hal::{Climate, ClimatePair, Clock, ConvertedRead, CurrentSensor, Dial, Scale, Shutdown}};
+
+/// the defaults are roughly an Excalibur 2400 with 1 kg of sliced fruit
+#[derive(Clone, Debug)]
+pub struct PlantParams {
+    /// W
+    pub heater_power : f64,
+    /// V rms
+    pub mains_voltage : f64,
+    /// A rms drawn by the fan whenever the dehydrator is on
+    pub fan_amps : f64,
+    /// °C at the ends of the dial
+    pub dial_min_temp : f64,
+    pub dial_max_temp : f64,
+    /// °C between the thermostat opening and closing
+    pub hysteresis : f64,
+    /// J/K of the air, trays and food
+    pub heat_capacity : f64,
+    /// W/K to the room through the walls and the exhaust
+    pub heat_loss : f64,
+    /// m³/s of room air pulled through by the fan, and when the fan is off
+    pub airflow : f64,
+    pub still_airflow : f64,
+    /// m³/s: evaporation per g/m³ below saturation while all the free water is left
+    pub mass_transfer : f64,
+    /// J/g
+    pub latent_heat : f64,
+    pub ambient : Climate,
+    /// g
+    pub tray_mass : f64,
+    pub dry_mass : f64,
+    pub water_mass : f64,
+    /// hx711 counts with the scale empty and per gram
+    pub hx711_offset : f64,
+    pub hx711_counts_per_gram : f64,
+    /// V rms per A rms from an ACS712 through [crate::rms::RmsCurrent]
+    pub acs712_volts_per_amp : f64,
+    /// seconds since the epoch when the simulation starts
+    pub start_time : i64,
+}
+
+impl Default for PlantParams {
+    fn default() -> Self {
+        PlantParams {
+            heater_power : 600.0,
+            mains_voltage : 120.0,
+            fan_amps : 0.25,
+            dial_min_temp : 35.0,
+            dial_max_temp : 74.0,
+            hysteresis : 3.0,
+            heat_capacity : 8000.0,
+            heat_loss : 10.0,
+            airflow : 0.02,
+            still_airflow : 0.001,
+            mass_transfer : 5e-4,
+            latent_heat : 2260.0,
+            ambient : Climate { temperature : 22.0, humidity : 50.0 },
+            tray_mass : 900.0,
+            dry_mass : 150.0,
+            water_mass : 850.0,
+            hx711_offset : 8000.0,
+            hx711_counts_per_gram : 420.0,
+            acs712_volts_per_amp : 0.185,
+            start_time : 1_700_000_000,
+        }
+    }
+}
+
+/// how simulated time relates to real time
+#[derive(Clone, Copy, Debug)]
+pub enum Pace {
+    /// time only moves in [Clock::delay_ms], which returns at once. For a single thread
+    /// calling [crate::control::sample_blob] and [crate::control::follow_profile] itself.
+    Stepped,
+    /// time runs this many times faster than the wall clock, and [Clock::delay_ms] sleeps
+    /// correspondingly less. For the threads of a server.
+    Accelerated(f64),
+}
+
+/// the state of the simulation, behind [Sim]
+#[derive(Clone, Debug)]
+pub struct Plant {
+    pub params : PlantParams,
+    /// seconds since `params.start_time`
+    pub time : f64,
+    /// °C in the chamber
+    pub temperature : f64,
+    /// g/m³ in the chamber
+    pub abs_humidity : f64,
+    /// g of free water left in the food
+    pub water : f64,
+    pub heater_on : bool,
+    /// false after [Shutdown::shutdown]
+    pub powered : bool,
+    pub shutdowns : u32,
+    /// the stepper's (min, max, pos) in half steps
+    pub dial : (i32, i32, i32),
+}
+
+impl Plant {
+    pub fn new(params : PlantParams) -> Self {
+        let ambient = params.ambient;
+        Plant {
+            time : 0.0,
+            temperature : ambient.temperature as f64,
+            abs_humidity : abs_humidity_g_per_m3(ambient.temperature, ambient.humidity) as f64,
+            water : params.water_mass,
+            heater_on : false,
+            powered : true,
+            shutdowns : 0,
+            dial : (0, 170, 0),
+            params,
+        }
+    }
+
+    /// °C that the thermostat holds for the current dial position
+    pub fn setpoint(&self) -> f64 {
+        let (min, max, pos) = self.dial;
+        let f = if max > min { (pos - min) as f64 / (max - min) as f64 } else { 0.0 };
+        let p = &self.params;
+        p.dial_min_temp + f.clamp(0.0, 1.0) * (p.dial_max_temp - p.dial_min_temp)
+    }
+
+    /// g/s leaving the food
+    pub fn evaporation(&self) -> f64 {
+        let p = &self.params;
+        let ambient = abs_humidity_g_per_m3(p.ambient.temperature, p.ambient.humidity) as f64;
+        let saturated = abs_humidity_g_per_m3(self.temperature as f32, 100.0) as f64;
+        // the vapour leaves with the air: w = ambient + e / q with e = g (saturated - w)
+        let g = p.mass_transfer * self.water / p.water_mass;
+        let q = self.airflow();
+        (g * q / (g + q) * (saturated - ambient)).max(0.0)
+    }
+
+    fn airflow(&self) -> f64 {
+        if self.powered { self.params.airflow } else { self.params.still_airflow }
+    }
+
+    /// one Euler step of `dt` seconds
+    fn step(&mut self, dt : f64) {
+        let p = self.params.clone();
+        let half = p.hysteresis / 2.0;
+        if !self.powered {
+            self.heater_on = false;
+        } else if self.temperature < self.setpoint() - half {
+            self.heater_on = true;
+        } else if self.temperature > self.setpoint() + half {
+            self.heater_on = false;
+        }
+
+        let e = self.evaporation().min(self.water / dt);
+        let heat = if self.heater_on { p.heater_power } else { 0.0 }
+            - p.heat_loss * (self.temperature - p.ambient.temperature as f64)
+            - p.latent_heat * e;
+        self.temperature += heat / p.heat_capacity * dt;
+        self.water -= e * dt;
+        self.abs_humidity = abs_humidity_g_per_m3(p.ambient.temperature, p.ambient.humidity) as f64
+            + e / self.airflow();
+        self.time += dt;
+    }
+
+    /// run for `seconds` in steps of at most one second
+    pub fn advance(&mut self, seconds : f64) {
+        let mut left = seconds;
+        while left > 0.0 {
+            let dt = left.min(1.0);
+            self.step(dt);
+            left -= dt;
+        }
+    }
+
+    pub fn inside(&self) -> Climate {
+        let saturated = abs_humidity_g_per_m3(self.temperature as f32, 100.0) as f64;
+        Climate {
+            temperature : self.temperature as f32,
+            humidity : (100.0 * self.abs_humidity / saturated).min(100.0) as f32,
+        }
+    }
+
+    /// g on the scale, trays included
+    pub fn grams(&self) -> f64 {
+        self.params.tray_mass + self.params.dry_mass + self.water
+    }
+
+    /// A rms
+    pub fn amps(&self) -> f64 {
+        let p = &self.params;
+        if !self.powered { return 0.0; }
+        p.fan_amps + if self.heater_on { p.heater_power / p.mains_voltage } else { 0.0 }
+    }
+}
+
+/// a handle on a shared [Plant]. Cloning it gives another view of the same dehydrator,
+/// so the measurement loop, the profile thread and the http handlers can each have one.
+#[derive(Clone)]
+pub struct Sim {
+    plant : Arc<Mutex<Plant>>,
+    pace : Pace,
+    started : Instant,
+}
+
+impl Sim {
+    pub fn new(params : PlantParams, pace : Pace) -> Self {
+        Sim { plant : Arc::new(Mutex::new(Plant::new(params))), pace, started : Instant::now() }
+    }
+
+    /// the plant, brought up to the current time
+    pub fn plant(&self) -> std::sync::MutexGuard<'_, Plant> {
+        let mut plant = self.plant.lock().unwrap();
+        if let Pace::Accelerated(speedup) = self.pace {
+            let t = self.started.elapsed().as_secs_f64() * speedup;
+            if t > plant.time {
+                plant.advance(t - plant.time);
+            }
+        }
+        plant
+    }
+
+    /// raw counts like the hx711 driver
+    pub fn scale(&self) -> SimScale {
+        SimScale(self.clone())
+    }
+
+    /// the rms volts of an ACS712 as [crate::rms::RmsCurrent] gives them. A single sample
+    /// of the sine would land on the same phase at every stepped read
+    pub fn current(&self) -> SimCurrent {
+        SimCurrent(self.clone())
+    }
+}
+
+impl ClimatePair for Sim {
+    fn read(&mut self) -> anyhow::Result<(Climate, Climate)> {
+        let plant = self.plant();
+        Ok((plant.inside(), plant.params.ambient))
+    }
+}
+
+impl Dial for Sim {
+    fn set_fraction(&mut self, f : f32) -> anyhow::Result<()> {
+        let mut plant = self.plant();
+        let (min, max, _) = plant.dial;
+        plant.dial.2 = min + (f * (max - min) as f32) as i32;
+        Ok(())
+    }
+    fn jog(&mut self, steps : i32) -> anyhow::Result<()> {
+        let mut plant = self.plant();
+        let (min, max, pos) = plant.dial;
+        plant.dial.2 = (pos + steps).clamp(min, max);
+        Ok(())
+    }
+    fn recalibrate(&mut self, length : i32) -> anyhow::Result<()> {
+        self.plant().dial = (0, length, 0);
+        Ok(())
+    }
+    fn position(&self) -> (i32, i32, i32) {
+        self.plant().dial
+    }
+}
+
+/// like the ir plug: the heater and fan go off for good
+impl Shutdown for Sim {
+    fn shutdown(&mut self) -> anyhow::Result<()> {
+        let mut plant = self.plant();
+        plant.powered = false;
+        plant.shutdowns += 1;
+        Ok(())
+    }
+}
+
+impl Clock for Sim {
+    fn now(&self) -> i64 {
+        let plant = self.plant();
+        plant.params.start_time + plant.time as i64
+    }
+    fn delay_ms(&self, ms : u32) {
+        match self.pace {
+            Pace::Stepped => self.plant().advance(ms as f64 / 1000.0),
+            Pace::Accelerated(speedup) =>
+                std::thread::sleep(Duration::from_secs_f64(ms as f64 / 1000.0 / speedup)),
+        }
+    }
+}
+
+pub struct SimScale(Sim);
+
+impl ConvertedRead for SimScale {
+    fn read(&mut self) -> anyhow::Result<f32> {
+        let plant = self.0.plant();
+        let p = &plant.params;
+        Ok((p.hx711_offset + plant.grams() * p.hx711_counts_per_gram).round() as f32)
+    }
+}
+
+impl Scale for SimScale {}
+
+pub struct SimCurrent(Sim);
+
+impl ConvertedRead for SimCurrent {
+    fn read(&mut self) -> anyhow::Result<f32> {
+        let plant = self.0.plant();
+        Ok((plant.amps() * plant.params.acs712_volts_per_amp) as f32)
+    }
+    fn peak(&mut self) -> Option<f32> {
+        let plant = self.0.plant();
+        Some((plant.amps() * plant.params.acs712_volts_per_amp * std::f64::consts::SQRT_2) as f32)
+    }
+}
+
+impl CurrentSensor for SimCurrent {}
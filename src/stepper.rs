@@ -1,19 +1,26 @@
-use esp_idf_hal::{peripheral::Peripheral, gpio::{InputPin, OutputPin, PinDriver, AnyOutputPin, self}, delay::FreeRtos};
+#[cfg(feature = "esp")]
+use esp_idf_hal::{peripheral::Peripheral, gpio::{InputPin, OutputPin, PinDriver, AnyOutputPin, self}};
 
+use crate::hal::Dial;
 
 trait Step = FnMut(Dir) -> anyhow::Result<()>;
 
-pub struct Stepper<'d> {
+/// energizes the coils for one half step, see [HalfStep]
+pub trait Coils {
+    fn activate(&mut self, dir : Dir) -> anyhow::Result<()>;
+}
+
+pub struct Stepper<C> {
     pub min : i32,
     pub max : i32,
     pub pos : i32,
-    step : HalfStep<'d>,
+    step : C,
     delay_ms : u32,
 }
 
-impl<'d> Stepper<'d> {
+impl<C : Coils> Stepper<C> {
     fn delay(&self) {
-        FreeRtos::delay_ms(self.delay_ms);
+        std::thread::sleep(std::time::Duration::from_millis(self.delay_ms as u64));
     }
 
     /// with delay
@@ -50,6 +57,24 @@ impl<'d> Stepper<'d> {
         self.set_pos(pos as i32)
     }
 
+    /// move by `steps` (negative is towards min), stopping at the limits
+    pub fn jog(&mut self, steps : i32) -> anyhow::Result<()> {
+        self.set_pos((self.pos + steps).clamp(self.min, self.max))
+    }
+
+    /// run against the lower limit again, which becomes position 0 of a range of `max` steps
+    pub fn recalibrate(&mut self, max : i32) -> anyhow::Result<()> {
+        // run against the lower limit the drive belt will slip
+        for _ in 0..self.max.max(max) {
+            self.delay();
+            self.step.activate(Dir::CW)?;
+        }
+        self.min = 0;
+        self.max = max;
+        self.pos = 0;
+        Ok(())
+    }
+
     pub fn set_pos(&mut self, pos : i32) -> anyhow::Result<()> {
         if pos < self.min || pos > self.max {
             anyhow::bail!("position out of range");
@@ -67,14 +92,16 @@ impl<'d> Stepper<'d> {
 
 pub enum Dir { CC, CW, Off }
 
-pub fn calibrate<'d>(step : HalfStep<'d>, max : i32) -> anyhow::Result<Stepper<'d>> {
-    let mut s = Stepper { min : 0, max, pos : 0, step, delay_ms : 20 };
+impl<C : Coils> Dial for Stepper<C> {
+    fn set_fraction(&mut self, f : f32) -> anyhow::Result<()> { Stepper::set_fraction(self, f) }
+    fn jog(&mut self, steps : i32) -> anyhow::Result<()> { Stepper::jog(self, steps) }
+    fn recalibrate(&mut self, length : i32) -> anyhow::Result<()> { Stepper::recalibrate(self, length) }
+    fn position(&self) -> (i32, i32, i32) { (self.min, self.max, self.pos) }
+}
 
-    // run against the lower limit the drive belt will slip
-    for i in 0..max {
-        s.delay();
-        s.step.activate(Dir::CW)?;
-    }
+pub fn calibrate<C : Coils>(step : C, max : i32) -> anyhow::Result<Stepper<C>> {
+    let mut s = Stepper { min : 0, max, pos : 0, step, delay_ms : 20 };
+    s.recalibrate(max)?;
     Ok(s)
 }
 
@@ -86,10 +113,10 @@ pub fn calibrate<'d>(step : HalfStep<'d>, max : i32) -> anyhow::Result<Stepper<'
 /// be consistent: when near the magnet
 /// they must either all have high or low levels it does
 /// not matter which in particular.
-pub fn calibrate_with_sensor<'d>(step : HalfStep<'d>,
+pub fn calibrate_with_sensor<C : Coils>(step : C,
                          at_min : impl Fn() -> bool,
                          at_mid : impl Fn() -> bool,
-                         at_max : impl Fn() -> bool) -> anyhow::Result<Stepper<'d>> {
+                         at_max : impl Fn() -> bool) -> anyhow::Result<Stepper<C>> {
 
     let b1 = at_min();
     let b2 = at_mid();
@@ -143,6 +170,7 @@ pub fn calibrate_with_sensor<'d>(step : HalfStep<'d>,
 
 /// for use with calibrate_with_sensor
 /// the closure returns true if the hall sensor GPIO is high
+#[cfg(feature = "esp")]
 pub fn mk_hall<'d> (pin : impl Peripheral<P=impl InputPin > + 'd) -> anyhow::Result<Box<impl Fn() -> bool + 'd>> {
     let hall = PinDriver::input(pin)?;
     // set pullup / down?
@@ -150,6 +178,7 @@ pub fn mk_hall<'d> (pin : impl Peripheral<P=impl InputPin > + 'd) -> anyhow::Res
 }
 
 
+#[cfg(feature = "esp")]
 pub struct HalfStep<'d> {
     pps : [PinDriver<'d, AnyOutputPin, gpio::Output>; 4],
     /// For each half step `i`, the lowest 4 bits of `pulse[i]` specify
@@ -163,6 +192,7 @@ pub struct HalfStep<'d> {
 /// according to <https://projecthub.arduino.cc/debanshudas23/1620bd1e-3463-4fb0-9c1c-53d03bb1a433>
 /// so a full rotation would be 512*20ms = 10.24s. This is fast enough since the dial is only 1/3
 /// of a rotation.
+#[cfg(feature = "esp")]
 impl<'d> HalfStep<'d> {
     pub fn init(a : impl OutputPin, b : impl OutputPin, c : impl OutputPin, d : impl OutputPin)
             -> anyhow::Result<Self> {
@@ -174,8 +204,11 @@ impl<'d> HalfStep<'d> {
         let pulse = [ 0x9,0x8,0xc,0x4,0x6,0x2,0x3,0x1 ];
         Ok(HalfStep { pps, pulse })
     }
+}
 
-    pub fn activate(&mut self, dir : Dir) -> anyhow::Result<()> {
+#[cfg(feature = "esp")]
+impl<'d> Coils for HalfStep<'d> {
+    fn activate(&mut self, dir : Dir) -> anyhow::Result<()> {
         static mut i : i8 = 0;
         use Dir::*;
         unsafe {
@@ -0,0 +1,290 @@
+//! Named blobs: one nvs namespace on the esp32, a map or a directory on the host. Calibrations,
+//! credentials and the measurement blobs all go through [BlobStore], so they are stored the
+//! same way whichever one is behind it.
+
+use std::{collections::BTreeMap, path::PathBuf};
+
+use anyhow::anyhow;
+use serde::{Serialize, de::DeserializeOwned};
+
+/// longest key nvs accepts
+pub const MAX_KEY_LEN : usize = 15;
+
+/// named blobs in flash (nvs) or somewhere else on the host
+pub trait BlobStore {
+    /// Ok(None) when there is no blob called `key`
+    fn get_blob(&self, key : &str) -> anyhow::Result<Option<Vec<u8>>>;
+    fn set_blob(&mut self, key : &str, blob : &[u8]) -> anyhow::Result<()>;
+    /// the names of all blobs, in no particular order
+    fn keys(&self) -> anyhow::Result<Vec<String>>;
+    /// Ok(false) when there was nothing to erase
+    fn erase(&mut self, key : &str) -> anyhow::Result<bool>;
+}
+
+/// the host stores reject what nvs would, so that mistakes show up before flashing
+fn check_key(key : &str) -> anyhow::Result<()> {
+    if key.is_empty() || key.len() > MAX_KEY_LEN || !key.bytes().all(|c| c.is_ascii_graphic() && c != b'/') {
+        return Err(anyhow!("invalid key {:?}: 1 to {} printable characters", key, MAX_KEY_LEN));
+    }
+    Ok(())
+}
+
+/// an nvs namespace. `EspNvs` doesn't remember which one it opened, which is needed to
+/// list the keys
+#[cfg(feature = "esp")]
+pub struct NvsStore<T : esp_idf_svc::nvs::NvsPartitionId> {
+    nvs : esp_idf_svc::nvs::EspNvs<T>,
+    partition : String,
+    namespace : String,
+}
+
+#[cfg(feature = "esp")]
+impl<T : esp_idf_svc::nvs::NvsPartitionId> NvsStore<T> {
+    /// `partition` is the label in partitions.csv, "nvs" for the default one
+    pub fn new(p : esp_idf_svc::nvs::EspNvsPartition<T>, partition : &str, namespace : &str) -> anyhow::Result<Self> {
+        Ok(NvsStore {
+            nvs : esp_idf_svc::nvs::EspNvs::new(p, namespace, true)?,
+            partition : partition.to_string(),
+            namespace : namespace.to_string(),
+        })
+    }
+}
+
+#[cfg(feature = "esp")]
+impl<T : esp_idf_svc::nvs::NvsPartitionId> BlobStore for NvsStore<T> {
+    fn get_blob(&self, key : &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let len = match self.nvs.blob_len(key)? {
+            Some(len) => len,
+            None => return Ok(None),
+        };
+        let mut buf = vec![0u8; len];
+        Ok(self.nvs.get_blob(key, &mut buf)?.map(|b| b.to_vec()))
+    }
+
+    /// set_blob calls nvs_commit
+    fn set_blob(&mut self, key : &str, blob : &[u8]) -> anyhow::Result<()> {
+        Ok(self.nvs.set_blob(key, blob)?)
+    }
+
+    fn keys(&self) -> anyhow::Result<Vec<String>> {
+        Ok(crate::nvs::Entries::new(&self.partition, &self.namespace)?.collect())
+    }
+
+    fn erase(&mut self, key : &str) -> anyhow::Result<bool> {
+        Ok(self.nvs.remove(key)?)
+    }
+}
+
+/// for the host
+#[derive(Default, Clone)]
+pub struct MemoryStore(pub BTreeMap<String, Vec<u8>>);
+
+impl BlobStore for MemoryStore {
+    fn get_blob(&self, key : &str) -> anyhow::Result<Option<Vec<u8>>> {
+        check_key(key)?;
+        Ok(self.0.get(key).cloned())
+    }
+    fn set_blob(&mut self, key : &str, blob : &[u8]) -> anyhow::Result<()> {
+        check_key(key)?;
+        self.0.insert(key.to_string(), blob.to_vec());
+        Ok(())
+    }
+    fn keys(&self) -> anyhow::Result<Vec<String>> {
+        Ok(self.0.keys().cloned().collect())
+    }
+    fn erase(&mut self, key : &str) -> anyhow::Result<bool> {
+        check_key(key)?;
+        Ok(self.0.remove(key).is_some())
+    }
+}
+
+/// one file per blob, for host tools that should remember things between runs
+pub struct DirStore(PathBuf);
+
+impl DirStore {
+    /// creates the directory if needed
+    pub fn new(dir : impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        Ok(DirStore(dir))
+    }
+}
+
+impl BlobStore for DirStore {
+    fn get_blob(&self, key : &str) -> anyhow::Result<Option<Vec<u8>>> {
+        check_key(key)?;
+        match std::fs::read(self.0.join(key)) {
+            Ok(b) => Ok(Some(b)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+    fn set_blob(&mut self, key : &str, blob : &[u8]) -> anyhow::Result<()> {
+        check_key(key)?;
+        // like nvs, a blob is either the old or the new one
+        let tmp = self.0.join(format!("{}.tmp", key));
+        std::fs::write(&tmp, blob)?;
+        std::fs::rename(tmp, self.0.join(key))?;
+        Ok(())
+    }
+    fn keys(&self) -> anyhow::Result<Vec<String>> {
+        let mut keys = Vec::new();
+        for entry in std::fs::read_dir(&self.0)? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if check_key(&name).is_ok() && !name.ends_with(".tmp") {
+                keys.push(name);
+            }
+        }
+        Ok(keys)
+    }
+    fn erase(&mut self, key : &str) -> anyhow::Result<bool> {
+        check_key(key)?;
+        match std::fs::remove_file(self.0.join(key)) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// cbor encode `value` as the blob `key`
+pub fn save<S : BlobStore + ?Sized>(store : &mut S, key : &str, value : &impl Serialize) -> anyhow::Result<()> {
+    let mut buf = Vec::new();
+    ciborium::ser::into_writer(value, &mut buf)?;
+    store.set_blob(key, &buf)
+}
+
+/// decode the blob `key`, Ok(None) if it doesn't exist
+pub fn load<S : BlobStore + ?Sized, T : DeserializeOwned>(store : &S, key : &str) -> anyhow::Result<Option<T>> {
+    match store.get_blob(key)? {
+        Some(blob) => Ok(Some(ciborium::de::from_reader(blob.as_slice())?)),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{Calibration, CalibrationTrigger, hal::ConvertedRead, linearly_calibrated::CalibratedSensor};
+
+    /// what every [BlobStore] should do
+    fn blobs(store : &mut dyn BlobStore) {
+        assert_eq!(store.get_blob("a").unwrap(), None);
+        store.set_blob("a", &[1, 2, 3]).unwrap();
+        store.set_blob("0000000z", &[]).unwrap();
+        assert_eq!(store.get_blob("a").unwrap(), Some(vec![1, 2, 3]));
+        assert_eq!(store.get_blob("0000000z").unwrap(), Some(vec![]));
+        store.set_blob("a", &[4]).unwrap();
+        assert_eq!(store.get_blob("a").unwrap(), Some(vec![4]));
+
+        let mut keys = store.keys().unwrap();
+        keys.sort();
+        assert_eq!(keys, ["0000000z", "a"]);
+
+        assert!(store.erase("a").unwrap());
+        assert!(!store.erase("a").unwrap());
+        assert_eq!(store.get_blob("a").unwrap(), None);
+        assert_eq!(store.keys().unwrap(), ["0000000z"]);
+
+        // nvs wouldn't take these either
+        for key in ["", "sixteen_chars_xx", "a/b", "a b"] {
+            assert!(store.set_blob(key, &[0]).is_err(), "{:?}", key);
+            assert!(store.get_blob(key).is_err(), "{:?}", key);
+            assert!(store.erase(key).is_err(), "{:?}", key);
+        }
+    }
+
+    fn calibration() -> Calibration {
+        let mut c = Calibration::new();
+        c.add_point(8000.0, 0.0).unwrap();
+        c.add_point(428_000.0, 1000.0).unwrap();
+        c
+    }
+
+    /// cbor through [save] and [load]
+    fn values(store : &mut dyn BlobStore) {
+        assert_eq!(load::<_, Calibration>(&*store, "HX711").unwrap(), None);
+        save(store, "HX711", &calibration()).unwrap();
+        assert_eq!(load::<_, Calibration>(&*store, "HX711").unwrap(), Some(calibration()));
+        save(store, "stepper_calib", &170i32).unwrap();
+        assert_eq!(load::<_, i32>(&*store, "stepper_calib").unwrap(), Some(170));
+        // the wrong type is an error rather than a default
+        assert!(load::<_, Calibration>(&*store, "stepper_calib").is_err());
+    }
+
+    struct Raw(f32);
+
+    impl ConvertedRead for Raw {
+        fn read(&mut self) -> anyhow::Result<f32> {
+            Ok(self.0)
+        }
+    }
+
+    /// a calibration saved by one sensor is the one the next boot loads
+    fn sensors(store : Arc<Mutex<dyn BlobStore + Send>>) {
+        let mut saved = CalibratedSensor::new(Raw(428_000.0), store.clone(), "HX711".to_string());
+        saved.calibration = calibration();
+        saved.save_calibration(1_700_000_000, CalibrationTrigger::Request).unwrap();
+
+        let mut loaded = CalibratedSensor::new(Raw(428_000.0), store, "HX711".to_string());
+        assert!(loaded.load_calibration().unwrap());
+        assert_eq!(loaded.calibration, calibration());
+        assert!((loaded.read().unwrap() - 1000.0).abs() < 1e-3);
+        assert_eq!(loaded.history().unwrap().records.len(), 1);
+    }
+
+    /// the ACS712 calibrated in adc counts by older firmware, now that it reads volts
+    #[test]
+    fn calibration_in_another_unit() {
+        let store : Arc<Mutex<dyn BlobStore + Send>> = Arc::new(Mutex::new(MemoryStore::default()));
+        let mut counts = CalibratedSensor::new(Raw(2100.0), store.clone(), "ACS712".to_string());
+        counts.calibration.add_point(1850.0, 0.0).unwrap();
+        counts.calibration.add_point(2350.0, 5.0).unwrap();
+        counts.save_calibration(1_700_000_000, CalibrationTrigger::Request).unwrap();
+
+        let mut volts = CalibratedSensor::new(Raw(0.4), store.clone(), "ACS712".to_string()).with_raw_max(3.3);
+        assert!(!volts.load_calibration().unwrap());
+        assert_eq!(volts.calibration, Calibration::new());
+        assert_eq!(volts.read().unwrap(), 0.4);
+        // gone from flash, and the history can't bring it back
+        assert_eq!(load::<_, Calibration>(&*store.lock().unwrap(), "ACS712").unwrap(), None);
+        assert!(volts.rollback(1_700_000_000, 1_700_000_100).is_err());
+
+        // one in volts is kept
+        volts.calibration.add_point(0.0, 0.0).unwrap();
+        volts.calibration.add_point(0.5, 5.0).unwrap();
+        volts.save_calibration(1_700_000_200, CalibrationTrigger::Request).unwrap();
+        let mut reloaded = CalibratedSensor::new(Raw(0.4), store, "ACS712".to_string()).with_raw_max(3.3);
+        assert!(reloaded.load_calibration().unwrap());
+        assert!((reloaded.read().unwrap() - 4.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn memory_store() {
+        blobs(&mut MemoryStore::default());
+        values(&mut MemoryStore::default());
+        sensors(Arc::new(Mutex::new(MemoryStore::default())));
+    }
+
+    /// a fresh directory under the system's temporary one
+    fn dir(name : &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("dehydrator-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn dir_store() {
+        let d = dir("store");
+        blobs(&mut DirStore::new(d.join("blobs")).unwrap());
+        values(&mut DirStore::new(d.join("values")).unwrap());
+        sensors(Arc::new(Mutex::new(DirStore::new(d.join("calib")).unwrap())));
+
+        // the blobs are still there for the next DirStore on the directory
+        let reopened = DirStore::new(d.join("values")).unwrap();
+        assert_eq!(load::<_, Calibration>(&reopened, "HX711").unwrap(), Some(calibration()));
+        std::fs::remove_dir_all(d).unwrap();
+    }
+}
@@ -0,0 +1,153 @@
+use crate::{Config, CurrentSource, FieldError, Hardware, TraySensor, ValidationErrors};
+
+/// shortest allowed `measurement_period_ms`: the SHT31s are in periodic mode at 1 measurement per second
+const MIN_PERIOD_MS : u32 = 1000;
+/// one measurement per hour
+const MAX_PERIOD_MS : u32 = 3_600_000;
+
+impl ValidationErrors {
+    fn push(&mut self, field : impl Into<String>, message : impl Into<String>) {
+        self.errors.push(FieldError { field : field.into(), message : message.into() });
+    }
+
+    /// for a body that doesn't even deserialize into the expected type
+    pub fn body(e : impl std::fmt::Display) -> Self {
+        Self::field("body", e.to_string())
+    }
+
+    /// a single invalid field
+    pub fn field(field : impl Into<String>, message : impl Into<String>) -> Self {
+        let mut v = ValidationErrors { errors : Vec::new() };
+        v.push(field, message);
+        v
+    }
+}
+
+/// as many as fit behind one TCA9548A, two on each channel
+const MAX_TRAYS : usize = 16;
+
+impl Hardware {
+    pub fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut v = ValidationErrors { errors : Vec::new() };
+        if let CurrentSource::Sct013 { turns, burden_ohms } = self.current {
+            if !(turns.is_finite() && turns >= 1.0) {
+                v.push("current.turns", "must be at least 1");
+            }
+            if !(burden_ohms.is_finite() && burden_ohms > 0.0) {
+                v.push("current.burden_ohms", "must be a positive number of ohms");
+            }
+        }
+
+        if self.trays.len() > MAX_TRAYS {
+            v.push("trays", format!("at most {} tray sensors", MAX_TRAYS));
+        }
+        // the inside and outside sensors are at 0x44 and 0x45 on the bus itself, a tray sensor
+        // at the same address is only apart from them behind a multiplexer, whose channels
+        // Sht::select closes before every access to the bus itself
+        let mut taken : Vec<(u8, [u8; 2])> = Vec::new();
+        for (i, t) in self.trays.iter().enumerate() {
+            let TraySensor { tray, address, mux } = *t;
+            if address != 0x44 && address != 0x45 {
+                v.push(format!("trays[{}].address", i), "must be 0x44 (68) or 0x45 (69)");
+            }
+            match mux {
+                Some([mux_address, channel]) => {
+                    if !(0x70..=0x77).contains(&mux_address) {
+                        v.push(format!("trays[{}].mux[0]", i), "a TCA9548A is at 0x70 (112) to 0x77 (119)");
+                    }
+                    if channel > 7 {
+                        v.push(format!("trays[{}].mux[1]", i), "channels are 0 to 7");
+                    }
+                    if taken.contains(&(address, [mux_address, channel])) {
+                        v.push(format!("trays[{}]", i), "another sensor has this address and channel");
+                    }
+                    taken.push((address, [mux_address, channel]));
+                },
+                None => v.push(format!("trays[{}].mux", i), "needed, the inside and outside sensors are at 0x44 and 0x45 on the bus itself"),
+            }
+            if self.trays[..i].iter().any(|u| u.tray == tray) {
+                v.push(format!("trays[{}].tray", i), format!("tray {} has a sensor already", tray));
+            }
+        }
+
+        if v.errors.is_empty() { Ok(()) } else { Err(v) }
+    }
+}
+
+impl Config {
+    /// Checks everything that would otherwise make the profile thread or the sampling loop misbehave.
+    /// `last_modified` is not checked since the server sets it.
+    ///
+    /// Unused entries of `step_times` are 0 after the used ones, like app.ts sends them.
+    pub fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut v = ValidationErrors { errors : Vec::new() };
+
+        if self.step_times[0] != 0 {
+            v.push("step_times[0]", "the first step must be at 0 s");
+        }
+        let used = self.step_times.iter().rposition(|&t| t != 0).map_or(1, |i| i + 1);
+        for i in 1..used {
+            if self.step_times[i] <= self.step_times[i-1] {
+                v.push(format!("step_times[{}]", i),
+                       format!("{} is not after the previous step at {}", self.step_times[i], self.step_times[i-1]));
+            }
+        }
+
+        for (i, f) in self.step_fracs.iter().enumerate() {
+            if !(0.0..=1.0).contains(f) {
+                v.push(format!("step_fracs[{}]", i), format!("{} is outside [0,1]", f));
+            }
+        }
+
+        if !(MIN_PERIOD_MS..=MAX_PERIOD_MS).contains(&self.measurement_period_ms) {
+            v.push("measurement_period_ms", format!("must be between {} and {}", MIN_PERIOD_MS, MAX_PERIOD_MS));
+        }
+
+        if self.n_wavelets == 0 || self.n_wavelets as usize > crate::meas::N1 {
+            v.push("n_wavelets", format!("must be between 1 and {}", crate::meas::N1));
+        }
+
+        if !(self.w_cut.is_finite() && self.w_cut > 0.0) {
+            v.push("w_cut", "must be a positive number of g/m3");
+        }
+
+        if !(0.0..=150.0).contains(&self.probe_target) {
+            v.push("probe_target", "must be between 0 (off) and 150 °C");
+        }
+
+        // Dead Sea shore to above Everest base camp
+        if self.pressure_kpa != 0.0 && !(40.0..=110.0).contains(&self.pressure_kpa) {
+            v.push("pressure_kpa", "must be 0 (sea level) or between 40 and 110 kPa");
+        }
+
+        if v.errors.is_empty() { Ok(()) } else { Err(v) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trays(trays : serde_json::Value) -> Result<(), ValidationErrors> {
+        let h : Hardware = serde_json::from_value(serde_json::json!({ "trays" : trays })).unwrap();
+        h.validate()
+    }
+
+    fn fields(r : Result<(), ValidationErrors>) -> Vec<String> {
+        r.err().map_or(Vec::new(), |v| v.errors.into_iter().map(|e| e.field).collect())
+    }
+
+    #[test]
+    fn tray_sensors() {
+        assert!(trays(serde_json::json!([{"tray":1,"address":68,"mux":[112,0]},
+                                         {"tray":2,"address":69,"mux":[112,0]},
+                                         {"tray":3,"address":68,"mux":[113,0]}])).is_ok());
+        // on the bus itself a tray sensor answers with inside or outside
+        assert_eq!(fields(trays(serde_json::json!([{"tray":1,"address":69}]))), ["trays[0].mux"]);
+        assert_eq!(fields(trays(serde_json::json!([{"tray":1,"address":68,"mux":[112,0]},
+                                                   {"tray":2,"address":68,"mux":[112,0]}]))), ["trays[1]"]);
+        assert_eq!(fields(trays(serde_json::json!([{"tray":1,"address":70,"mux":[100,8]},
+                                                   {"tray":1,"address":68,"mux":[112,1]}]))),
+                   ["trays[0].address", "trays[0].mux[0]", "trays[0].mux[1]", "trays[1].tray"]);
+    }
+}
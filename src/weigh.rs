@@ -0,0 +1,155 @@
+//! The scale reading the way a kitchen scale shows it. Each measurement is several HX711
+//! samples reduced by a trimmed mean, which drops the fan's vibration and single bad
+//! conversions. A jump of more than `spike_g` is held back until `settle` readings agree on
+//! the new weight, so lifting a tray out doesn't show up as a loss of water, and the weight
+//! is "stable" once `stable_for` readings stayed within `stable_g`.
+
+use std::collections::VecDeque;
+
+use anyhow::anyhow;
+
+use crate::linearly_calibrated::CalibratedSensor;
+
+#[derive(Clone, Copy, Debug)]
+pub struct WeighParams {
+    /// HX711 samples per reading, at 10 per second
+    pub samples : usize,
+    /// fraction dropped from each end before averaging, 0.5 is the median
+    pub trim : f32,
+    pub spike_g : f32,
+    pub settle : usize,
+    pub stable_g : f32,
+    pub stable_for : usize,
+}
+
+impl Default for WeighParams {
+    fn default() -> Self {
+        WeighParams { samples : 8, trim : 0.25, spike_g : 50.0, settle : 3, stable_g : 2.0, stable_for : 5 }
+    }
+}
+
+/// mean of the middle of `xs`, after sorting it
+pub fn trimmed_mean(xs : &mut [f32], trim : f32) -> f32 {
+    xs.sort_by(|a, b| a.total_cmp(b));
+    let n = xs.len();
+    let k = ((n as f32 * trim.clamp(0.0, 0.5)) as usize).min((n - 1) / 2);
+    let middle = &xs[k..n - k];
+    middle.iter().sum::<f32>() / middle.len() as f32
+}
+
+fn range(xs : impl Iterator<Item = f32>) -> f32 {
+    let (lo, hi) = xs.fold((f32::INFINITY, f32::NEG_INFINITY), |(lo, hi), x| (lo.min(x), hi.max(x)));
+    hi - lo
+}
+
+pub struct Weigher {
+    pub params : WeighParams,
+    /// the weight shown
+    accepted : Option<f32>,
+    /// readings after a jump, until they agree
+    pending : Vec<f32>,
+    recent : VecDeque<f32>,
+    pub stable : bool,
+    /// readings held back as spikes since boot
+    pub rejected : u32,
+}
+
+impl Weigher {
+    pub fn new(params : WeighParams) -> Self {
+        Weigher { params, accepted : None, pending : Vec::new(), recent : VecDeque::new(), stable : false, rejected : 0 }
+    }
+
+    /// grams from `params.samples` samples of `scale`. Samples that fail or are NaN are
+    /// skipped, it is an error only when all of them are
+    pub fn read(&mut self, scale : &mut CalibratedSensor) -> anyhow::Result<f32> {
+        let mut xs = Vec::with_capacity(self.params.samples);
+        let mut err = None;
+        for _ in 0..self.params.samples.max(1) {
+            match scale.read() {
+                Ok(x) if x.is_finite() => xs.push(x),
+                Ok(_) => (),
+                Err(e) => err = Some(e),
+            }
+        }
+        if xs.is_empty() {
+            return Err(err.unwrap_or_else(|| anyhow!("{}: every sample was NaN", scale.name)));
+        }
+        Ok(self.update(trimmed_mean(&mut xs, self.params.trim)))
+    }
+
+    /// the weight to show after reading `x`
+    pub fn update(&mut self, x : f32) -> f32 {
+        let p = self.params;
+        let shown = match self.accepted {
+            Some(a) if (x - a).abs() > p.spike_g => {
+                // only the last `settle` can agree on a new weight
+                self.pending.push(x);
+                let excess = self.pending.len().saturating_sub(p.settle.max(1));
+                self.pending.drain(..excess);
+                let n = self.pending.len();
+                if n >= p.settle && range(self.pending[n - p.settle..].iter().copied()) <= p.spike_g {
+                    // the weight really changed
+                    self.pending.clear();
+                    x
+                } else {
+                    self.rejected += 1;
+                    a
+                }
+            },
+            _ => {
+                self.pending.clear();
+                x
+            },
+        };
+        self.accepted = Some(shown);
+
+        self.recent.push_back(x);
+        while self.recent.len() > p.stable_for {
+            self.recent.pop_front();
+        }
+        self.stable = self.pending.is_empty() && self.recent.len() == p.stable_for &&
+            range(self.recent.iter().copied()) <= p.stable_g;
+        shown
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trimmed() {
+        assert_eq!(trimmed_mean(&mut [3.0, 1.0, 2.0, 100.0], 0.25), 2.5);
+        assert_eq!(trimmed_mean(&mut [5.0, -40.0, 4.0, 6.0, 4.0], 0.5), 4.0);
+        assert_eq!(trimmed_mean(&mut [7.0], 0.25), 7.0);
+    }
+
+    #[test]
+    fn spike_held_back_until_it_settles() {
+        let mut w = Weigher::new(Default::default());
+        assert_eq!(w.update(1000.0), 1000.0);
+        // a tray lifted out for two readings
+        assert_eq!(w.update(700.0), 1000.0);
+        assert_eq!(w.update(701.0), 1000.0);
+        assert_eq!(w.update(999.0), 999.0);
+        assert_eq!(w.rejected, 2);
+        // taken off for good
+        for _ in 0..2 {
+            assert_eq!(w.update(500.0), 999.0);
+        }
+        assert_eq!(w.update(500.0), 500.0);
+    }
+
+    #[test]
+    fn pending_stays_bounded() {
+        let mut w = Weigher::new(Default::default());
+        w.update(1000.0);
+        // jumps that never agree, like a scale knocked about
+        for i in 0..1000 {
+            let x = if i % 2 == 0 { 0.0 } else { 2000.0 };
+            assert_eq!(w.update(x), 1000.0);
+            assert!(w.pending.len() <= w.params.settle);
+        }
+        assert!(!w.stable);
+    }
+}
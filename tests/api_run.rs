@@ -0,0 +1,94 @@
+//! When the handlers of api.rs start a run, on the simulated dehydrator:
+//!
+//! `cargo test --no-default-features --features host --target x86_64-unknown-linux-gnu`
+#![cfg(feature = "host")]
+
+use std::sync::{Arc, Mutex};
+
+use dehydrator::{*, api::Shared, auth::Auth, hal::Clock, linearly_calibrated::CalibratedSensor,
+                 router::{Router, HttpRequest}, sim::{Pace, PlantParams, Sim}, store::{BlobStore, MemoryStore}};
+
+/// the handlers on a fresh simulation, with no password so every endpoint is open
+fn api() -> (Sim, Shared, Router) {
+    let sim = Sim::new(PlantParams::default(), Pace::Stepped);
+    let calib : Arc<Mutex<dyn BlobStore + Send>> = Arc::new(Mutex::new(MemoryStore::default()));
+    let auth = Arc::new(Auth::load(Arc::new(Mutex::new(MemoryStore::default()))).unwrap());
+    let shared = Shared {
+        config : Arc::new(Mutex::new(Config::initial(sim.now()))),
+        step_index_completed : Arc::new(Mutex::new(0)),
+        manual_stepper : Arc::new(Mutex::new(false)),
+        dial : Arc::new(Mutex::new(sim.clone())),
+        shutdown : Arc::new(Mutex::new(sim.clone())),
+        calibrated_sensors : Arc::new(Mutex::new([CalibratedSensor::new(sim.current(), calib.clone(), "ACS712".to_string()),
+                                                  CalibratedSensor::new(sim.scale(), calib.clone(), "HX711".to_string())])),
+        calib,
+        comp : Arc::new(Mutex::new(MemoryStore::default())),
+        auth : auth.clone(),
+        status : Arc::new(Mutex::new(Status::default())),
+        clock : Arc::new(sim.clone()),
+        run : Arc::new(Mutex::new(Default::default())),
+    };
+    let mut router = Router::new(auth);
+    api::register(&mut router, &shared);
+    (sim, shared, router)
+}
+
+fn post(router : &Router, route : &routes::Route, body : &serde_json::Value) {
+    let rq = HttpRequest { headers : Vec::new(), body : serde_json::to_vec(body).unwrap() };
+    let rsp = router.find("POST", &route.uri()).unwrap()(&rq);
+    assert_eq!(rsp.status, 200, "POST {}", route.uri());
+}
+
+/// the initial profile, zeroing the scale at the start
+fn config(now : i64) -> serde_json::Value {
+    let mut c = serde_json::to_value(Config::initial(now)).unwrap();
+    c["zero_scale_at_start"] = serde_json::json!(true);
+    c
+}
+
+fn zero_shifts(shared : &Shared) -> usize {
+    shared.calibrated_sensors.lock().unwrap()[1].zero_shifts.len()
+}
+
+#[test]
+fn zeroes_only_when_a_run_starts() {
+    let (sim, shared, router) = api();
+    let mut c = config(sim.now());
+
+    // the first profile starts a run
+    post(&router, &routes::CONFIG_POST, &c);
+    assert_eq!(zero_shifts(&shared), 1);
+    assert_eq!(shared.run.lock().unwrap().number, 1);
+
+    // posted again during the run, the same and with a later step changed, the food
+    // is on the scale
+    post(&router, &routes::CONFIG_POST, &c);
+    *shared.step_index_completed.lock().unwrap() = 1;
+    post(&router, &routes::CONFIG_POST, &c);
+    assert_eq!(*shared.step_index_completed.lock().unwrap(), 0);
+    c["step_fracs"][1] = serde_json::json!(0.5);
+    post(&router, &routes::CONFIG_POST, &c);
+    assert_eq!(zero_shifts(&shared), 1);
+    assert_eq!(shared.run.lock().unwrap().number, 1);
+
+    // the run ended, as control::follow_run does once dry
+    shared.run.lock().unwrap().going = false;
+    post(&router, &routes::CONFIG_POST, &c);
+    assert_eq!(zero_shifts(&shared), 2);
+    assert_eq!(shared.run.lock().unwrap().number, 2);
+
+    post(&router, &routes::RESTART, &serde_json::Value::Null);
+    assert_eq!(zero_shifts(&shared), 3);
+    assert_eq!(shared.run.lock().unwrap().number, 3);
+}
+
+#[test]
+fn no_zero_unless_configured() {
+    let (sim, shared, router) = api();
+    let mut c = config(sim.now());
+    c["zero_scale_at_start"] = serde_json::json!(false);
+    post(&router, &routes::CONFIG_POST, &c);
+    post(&router, &routes::RESTART, &serde_json::Value::Null);
+    assert_eq!(zero_shifts(&shared), 0);
+    assert_eq!(shared.run.lock().unwrap().number, 2);
+}
@@ -0,0 +1,228 @@
+//! The measurement loop of main.rs on the simulated dehydrator, from a cold start until it
+//! shuts down for being dry, in simulated time:
+//!
+//! `cargo test --no-default-features --features host --target x86_64-unknown-linux-gnu`
+#![cfg(feature = "host")]
+
+use std::sync::{Arc, Mutex};
+
+use dehydrator::{*, control::Run, fault::{Faults, Faulty}, hal::{Clock, Probes, Shutdown}, linearly_calibrated::CalibratedSensor,
+                 meas::{Meas, N1}, nvs::{self, Key}, sim::{Pace, PlantParams, Sim}, store::{BlobStore, MemoryStore}, weigh::Weigher};
+
+/// the dial all the way up, and dry once the chamber air is within 0.3 g/m³ of the room's
+fn config(now : i64) -> Config {
+    let mut c = serde_json::to_value(Config::initial(now)).unwrap();
+    c["step_fracs"][0] = serde_json::json!(1.0);
+    c["w_cut"] = serde_json::json!(10.0);
+    serde_json::from_value(c).unwrap()
+}
+
+/// what main.rs shares between its threads, and the state of its measurement loop
+struct Dehydrator {
+    sim : Sim,
+    climate : Faulty<Sim>,
+    config : Mutex<Config>,
+    step_index_completed : Mutex<usize>,
+    manual : Mutex<bool>,
+    dial : Mutex<Sim>,
+    shutdown : Mutex<Sim>,
+    sensors : Mutex<[CalibratedSensor<'static>; 2]>,
+    status : Mutex<Status>,
+    comp : Mutex<Faulty<MemoryStore>>,
+    run : Mutex<Run>,
+
+    meas : Meas<[f32; N1]>,
+    weigher : Weigher,
+    seen : u32,
+    dry : bool,
+    reached : Vec<bool>,
+    j : Key,
+    /// blobs that couldn't be stored, and samples without current or weight, in every run
+    lost : usize,
+    sensor_errors : i32,
+    climate_errors : i32,
+}
+
+impl Dehydrator {
+    /// `faults` for the climate, current and scale, and the writes of the blobs
+    fn new(faults : [Faults; 4]) -> Self {
+        let [climate, current, scale, comp] = faults;
+        let sim = Sim::new(PlantParams::default(), Pace::Stepped);
+        let calib : Arc<Mutex<dyn BlobStore + Send>> = Arc::new(Mutex::new(MemoryStore::default()));
+        let comp = Mutex::new(Faulty::new(MemoryStore::default(), comp));
+        let j = control::last_key(&comp).unwrap();
+        Dehydrator {
+            config : Mutex::new(config(sim.now())),
+            step_index_completed : Mutex::new(0),
+            manual : Mutex::new(false),
+            dial : Mutex::new(sim.clone()),
+            shutdown : Mutex::new(sim.clone()),
+            sensors : Mutex::new([CalibratedSensor::new(Faulty::new(sim.current(), current), calib.clone(), "ACS712".to_string()),
+                                  CalibratedSensor::new(Faulty::new(sim.scale(), scale), calib, "HX711".to_string())]),
+            status : Mutex::new(Status::default()),
+            comp,
+            run : Mutex::new(Run::default()),
+            meas : Meas::new(),
+            weigher : Weigher::new(Default::default()),
+            seen : 0,
+            dry : false,
+            reached : Vec::new(),
+            j,
+            lost : 0,
+            sensor_errors : 0,
+            climate_errors : 0,
+            climate : Faulty::new(sim.clone(), climate),
+            sim,
+        }
+    }
+
+    fn clean() -> Self {
+        Dehydrator::new(Default::default())
+    }
+
+    /// one pass of the measurement loop, with the profile thread's pass. Only storing the
+    /// blob may fail
+    fn blob(&mut self) {
+        if control::follow_run(&self.run, &mut self.seen, &mut self.dry) {
+            self.reached.clear();
+        }
+        control::follow_profile(&self.config, &self.step_index_completed, &self.manual, &self.dial, self.sim.now()).unwrap();
+        let mut probes : Vec<Box<dyn Probes + Send>> = Vec::new();
+        control::sample_blob(&mut self.meas, &mut self.climate, &self.sensors, &mut self.weigher, &mut probes,
+                             &self.config, &self.status, &self.sim).unwrap();
+        self.sensor_errors += self.meas.sensor_errors;
+        self.climate_errors += self.meas.climate_errors;
+        control::track_probes(&self.meas, &self.config, &mut self.reached);
+        control::shutdown_if_dry(&self.meas, &self.reached, &self.run, &self.shutdown, &self.sensors, &mut self.dry).unwrap();
+        if control::store_blob(&self.comp, &mut self.j, &self.meas).is_err() {
+            self.lost += 1;
+        }
+    }
+
+    /// blobs until the next shutdown
+    fn until_shutdown(&mut self, max : usize) -> usize {
+        let before = self.sim.plant().shutdowns;
+        for n in 1..=max {
+            self.blob();
+            if self.sim.plant().shutdowns > before {
+                return n;
+            }
+        }
+        panic!("not shut down after {} blobs, {:.0} g of water left", max, self.sim.plant().water);
+    }
+}
+
+#[test]
+fn shuts_down_once_dry() {
+    let mut d = Dehydrator::clean();
+    d.run.lock().unwrap().start();
+    let n = d.until_shutdown(400);
+
+    let plant = d.sim.plant();
+    // the chamber warms up through the first blobs, which aren't dry
+    assert!(n > 10, "shut down after {} blobs", n);
+    assert!(plant.water < 0.15 * plant.params.water_mass, "{:.0} g of water left", plant.water);
+    assert!(!plant.powered && plant.shutdowns == 1);
+    assert!(plant.setpoint() > 70.0, "the dial is at {:?}", plant.dial);
+    drop(plant);
+
+    assert_eq!(nvs::comp_keys(&*d.comp.lock().unwrap()).unwrap().len(), n);
+    assert!(d.meas.grams.iter().all(|g| g.is_finite()));
+    assert_eq!(d.meas.climate_errors, 0);
+
+    // the run ends with the shutdown
+    d.blob();
+    assert!(!d.run.lock().unwrap().going);
+    assert_eq!(d.sim.plant().shutdowns, 1);
+}
+
+#[test]
+fn shuts_down_again_in_the_next_run() {
+    let mut d = Dehydrator::clean();
+    d.run.lock().unwrap().start();
+    d.until_shutdown(400);
+
+    // fresh food, the plug back on and POST /restart
+    {
+        let mut plant = d.sim.plant();
+        plant.water = plant.params.water_mass;
+        plant.powered = true;
+    }
+    d.run.lock().unwrap().start();
+    *d.step_index_completed.lock().unwrap() = 0;
+    let n = d.until_shutdown(400);
+    assert!(n > 1, "the second run shut down after {} blobs", n);
+    assert_eq!(d.sim.plant().shutdowns, 2);
+}
+
+#[test]
+fn reads_the_current_while_heating() {
+    let mut d = Dehydrator::clean();
+    d.run.lock().unwrap().start();
+    let mut heating = 0;
+    for _ in 0..50 {
+        d.blob();
+        // the last sample was read with the plant as it is now
+        let plant = d.sim.plant();
+        let p = &plant.params;
+        let volts = (p.fan_amps + if plant.heater_on { p.heater_power / p.mains_voltage } else { 0.0 }) * p.acs712_volts_per_amp;
+        let a = d.meas.amps[N1 - 1] as f64;
+        assert!((a - volts).abs() < 1e-4, "{} read, {} with the heater on: {}", a, volts, plant.heater_on);
+        assert!(a > 0.0);
+        heating += plant.heater_on as usize;
+    }
+    // warming up the chamber
+    assert!(heating > 10, "the heater was on for {} blobs", heating);
+}
+
+#[test]
+fn no_shutdown_without_a_run() {
+    // the profile runs as at boot, with no config posted, and the chamber air gets dry
+    let mut d = Dehydrator::clean();
+    for _ in 0..300 {
+        d.blob();
+    }
+    assert!(control::dry_enough(d.meas.cutoffs, N1), "the air isn't dry, the test shows nothing");
+    assert_eq!(d.sim.plant().shutdowns, 0);
+    assert!(d.sim.plant().powered);
+}
+
+#[test]
+fn no_shutdown_after_shutting_down_by_hand() {
+    let mut d = Dehydrator::clean();
+    d.run.lock().unwrap().start();
+    for _ in 0..10 {
+        d.blob();
+    }
+    // POST /shutdown
+    d.shutdown.lock().unwrap().shutdown().unwrap();
+    d.run.lock().unwrap().going = false;
+    for _ in 0..300 {
+        d.blob();
+    }
+    assert_eq!(d.sim.plant().shutdowns, 1);
+}
+
+#[test]
+fn keeps_measuring_through_faults() {
+    let faults = |seed, f : Faults| Faults { seed, ..f };
+    // a single missing climate sample keeps a blob from being dry, so those are rare
+    let mut d = Dehydrator::new([faults(1, Faults { error : 0.002, ..Default::default() }),
+                                 faults(2, Faults { error : 0.1, nan : 0.05, stuck : 0.01, stuck_for : 5, ..Default::default() }),
+                                 // all 8 samples of a weight fail now and then
+                                 faults(3, Faults { error : 0.5, ..Default::default() }),
+                                 faults(4, Faults { write_error : 0.1, ..Default::default() })]);
+    d.run.lock().unwrap().start();
+    let n = d.until_shutdown(400);
+
+    let plant = d.sim.plant();
+    assert!(plant.water < 0.15 * plant.params.water_mass, "{:.0} g of water left", plant.water);
+    drop(plant);
+    assert!(d.sensor_errors > 0 && d.climate_errors > 0, "{} {}", d.sensor_errors, d.climate_errors);
+    assert!(d.lost > 0);
+    // every blob that could be written is there
+    assert_eq!(nvs::comp_keys(&*d.comp.lock().unwrap()).unwrap().len(), n - d.lost);
+    // a sample that failed is NaN, the others are still in the blob
+    assert!(d.meas.amps.iter().any(|a| a.is_finite()));
+    assert!(d.meas.grams.iter().any(|g| g.is_finite()));
+}
@@ -4,9 +4,10 @@ import { getRelativePosition } from 'chart.js/helpers';
 
 import { types } from "./json.js";
 
-// GET and POST data relative to the current URL
-const configDataUrl = window.location.href + "/config";
-// const measurementDataURL = window.location.href + "/measurement.csv";
+// GET and POST data relative to the current URL. www/routes.json lists the endpoints
+const api = "/api/v1";
+const configDataUrl = api + "/config";
+// const measurementDataURL = api + "/measurement.csv";
 
         
 // for piecewiseConstant
@@ -106,6 +107,10 @@ function submitData() {
                 last_modified: 0
         }
         request.send(JSON.stringify(response));
+        if (request.status == 400) {
+                const v : types.ValidationErrors = JSON.parse(request.responseText);
+                alert("configuration rejected:\n" + v.errors.map(e => `${e.field}: ${e.message}`).join("\n"));
+        }
 };
 
 // global map from rounded time values to [time, Temperature] pairs
@@ -327,21 +332,23 @@ function submitCalibration() {
         }
         // submit POST request
         var request = new XMLHttpRequest();
-        request.open("POST", "/calib", true);
+        request.open("POST", api + "/calib", true);
         request.setRequestHeader("Content-Type", "application/json");
         request.send(JSON.stringify(data));
 }
 
 // retrieve calibration data from server with a GET to /calib,
 // and store it in the calibration form
-// not quite right because calibration data is a pair of x and y points
-// the server sends an array of objects with x0 x1 y0 and y1 properties
+// the server sends an array of CalibrationReport: the points, the fit and its R²
 function getCalibration() {
         var request = new XMLHttpRequest();
-        request.open("GET", "/calib", true);
+        request.open("GET", api + "/calib", true);
         request.onload = function() {
                 const data = JSON.parse(this.response);
-                function to_str(obj) { `x,y=${obj.x0},${obj.y0}; x,y=${obj.x1},${obj.y1}` };
+                function to_str(obj) {
+                        const points = obj.calibration.points.map(([x, y]) => `${x},${y}`).join("; ");
+                        return `${obj.calibration.fit} x,y=${points} R²=${obj.r_squared}`;
+                };
                 document.getElementById("calibration_1").value = to_str(data[0]);
                 document.getElementById("calibration_2").value = to_str(data[1]);
         };